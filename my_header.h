@@ -3,6 +3,75 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Brightness of a led that is turned on without giving one.
+ */
+#define BRIGHTNESS_FULL 255
+
+/**
+ * Most numeric arguments a state may carry, e.g. the three channels of "rgb 1 2 3".
+ * Bounds the work spent on a single command.
+ */
+#define MAX_ARGS 4
+
+/**
+ * Number of leds on the board.
+ */
+#define MAX_LED 4
+
+/**
+ * Longest name of a group defined with "esp def", in bytes.
+ */
+#define MAX_GROUP_NAME 8
+
+/**
+ * Longest command, in bytes, that needs to be held in a buffer by the parser.
+ */
+#define MAX_FRAME_LEN 64
+
+/**
+ * State code of `Command::opcode` for the states carrying a value.
+ */
+#define OPCODE_PAYLOAD 255
+
+/**
+ * High byte of `Command::opcode` for commands that are not `CommandKind::Set`.
+ */
+#define OPCODE_KIND 65280
+
+/**
+ * Bit of `Command::diff` for a changed led.
+ */
+#define DIFF_LED (1 << 0)
+
+/**
+ * Bit of `Command::diff` for a changed state, e.g. On to Blink.
+ */
+#define DIFF_STATE (1 << 1)
+
+/**
+ * Bit of `Command::diff` for the same state with another value, e.g. Blink 500 to Blink 250.
+ */
+#define DIFF_PAYLOAD (1 << 2)
+
+/**
+ * Priority of a command without a priority modifier.
+ */
+#define PRIORITY_NORMAL 0
+
+/**
+ * Priority of a command with the "!lo" modifier.
+ */
+#define PRIORITY_LOW 1
+
+/**
+ * Priority of a command with the "!hi" modifier.
+ */
+#define PRIORITY_HIGH 2
+
+/**
+ * Represents the four led's on the board.
+ */
 typedef enum Led {
   Led1,
   Led2,
@@ -10,15 +79,297 @@ typedef enum Led {
   Led4,
 } Led;
 
-typedef enum LedState {
+/**
+ * State of an Led.
+ * # To know:
+ * Without the default feature `extended-states` only On, Off and Toggle exist, which keeps
+ * `Command` small for on/off-only firmwares. The C layout follows the feature, the header
+ * has the other states only if `CMD_EXTENDED_STATES` is defined, see cbindgen.toml.
+ */
+typedef enum LedState_Tag {
+  /**
+   * On with the given brightness, "on 128". Plain "on" is `BRIGHTNESS_FULL`, see
+   * `LedState::ON`.
+   * # To know:
+   * In C this is a tagged struct, the discriminant followed by the brightness byte.
+   * The size of LedState is unchanged, a larger variant already sets it.
+   */
   On,
   Off,
+  /**
+   * Flips the led, "toggle". An off led turns on, any other state turns off, see
+   * `Command::apply`.
+   */
+  Toggle,
+#if defined(CMD_EXTENDED_STATES)
+  /**
+   * Blinks with the given period, "blink 500ms".
+   */
+  Blink,
+#endif
+#if defined(CMD_EXTENDED_STATES)
+  /**
+   * Fades to off over the given duration, "fadeout 1000" for a graceful shutdown.
+   */
+  FadeOut,
+#endif
+#if defined(CMD_EXTENDED_STATES)
+  /**
+   * Shows a color, "rgb 255 128 0".
+   */
+  Rgb,
+#endif
+#if defined(CMD_EXTENDED_STATES)
+  /**
+   * Steps through the brightness values, "ramp 0 64 128 255". Only the first `count`
+   * values are used, the others are 0.
+   */
+  Ramp,
+#endif
+#if defined(CMD_EXTENDED_STATES)
+  /**
+   * Plays the on/off frames of a flash code, "pattern 10110". The first frame is the
+   * highest of the `len` low bits of `bits`, a set bit is on.
+   */
+  Pattern,
+#endif
+} LedState_Tag;
+
+typedef struct On_Body {
+  uint8_t brightness;
+} On_Body;
+
+#if defined(CMD_EXTENDED_STATES)
+typedef struct Blink_Body {
+  uint16_t period_ms;
+} Blink_Body;
+#endif
+
+#if defined(CMD_EXTENDED_STATES)
+typedef struct FadeOut_Body {
+  uint16_t duration_ms;
+} FadeOut_Body;
+#endif
+
+#if defined(CMD_EXTENDED_STATES)
+typedef struct Rgb_Body {
+  uint8_t r;
+  uint8_t g;
+  uint8_t b;
+} Rgb_Body;
+#endif
+
+#if defined(CMD_EXTENDED_STATES)
+typedef struct Ramp_Body {
+  uint8_t values[MAX_ARGS];
+  uint8_t count;
+} Ramp_Body;
+#endif
+
+#if defined(CMD_EXTENDED_STATES)
+typedef struct Pattern_Body {
+  uint16_t bits;
+  uint8_t len;
+} Pattern_Body;
+#endif
+
+typedef struct LedState {
+  LedState_Tag tag;
+  union {
+    On_Body on;
+#if defined(CMD_EXTENDED_STATES)
+    Blink_Body blink;
+#endif
+#if defined(CMD_EXTENDED_STATES)
+    FadeOut_Body fade_out;
+#endif
+#if defined(CMD_EXTENDED_STATES)
+    Rgb_Body rgb;
+#endif
+#if defined(CMD_EXTENDED_STATES)
+    Ramp_Body ramp;
+#endif
+#if defined(CMD_EXTENDED_STATES)
+    Pattern_Body pattern;
+#endif
+  };
 } LedState;
 
+/**
+ * The different kinds of commands.
+ */
+typedef enum CommandKind_Tag {
+  /**
+   * Puts a led in a state, e.g. "esp led1 on".
+   */
+  Set,
+  /**
+   * Asks for the state of all the leds, "esp status". See `fill_status`.
+   */
+  Status,
+  /**
+   * Turns every led off, "esp clear". Also the kind of `Command::safe_default`.
+   * Unlike a reset, which would restore the board's default states, the leds end up off
+   * whatever their defaults are.
+   */
+  Clear,
+  /**
+   * Asks the board to identify itself on a shared bus, "esp whoami". The firmware blinks
+   * a pattern and reports its address.
+   */
+  Identify,
+  /**
+   * Does nothing, "esp noop" or "esp ping". Keep-alive frames that the firmware
+   * acknowledges without changing the leds.
+   */
+  Noop,
+  /**
+   * Turns the leds on or off at once, "esp mask 5". Bit 0 is Led1, bit 1 Led2 and so on,
+   * 5 turns Led1 and Led3 on and the others off. See `Command::apply`.
+   * Carrying the bits makes CommandKind a tagged struct in C, the kind is `kind.tag`.
+   */
+  Mask,
+  /**
+   * Toggles every led, "esp invert", for a quick visual test. See `Command::apply`.
+   */
+  InvertAll,
+  /**
+   * Defines a group of leds at runtime, "esp def front led1 led2". Only the first
+   * `count` leds and `name_len` bytes of the name are used, the others are Led1 and 0.
+   * Storing the group and expanding it, see `parse_group`, is up to the firmware.
+   */
+  DefineGroup,
+  /**
+   * Tells the firmware to simulate a fault condition, "esp fault 3", for fault testing.
+   * Only test builds of the firmware act on it, the parser accepts it everywhere.
+   */
+  Fault,
+  /**
+   * Asks the firmware to list the supported commands, a bare "esp" typed by an operator.
+   * With a space, "esp " fails as `ParseError::EmptyCommand`.
+   */
+  Help,
+  /**
+   * Lights Led1 to Led4 in turn, each for `step_ms` and then off again, "esp cycle 200".
+   * All leds are off when it is done.
+   */
+  Cycle,
+} CommandKind_Tag;
+
+typedef struct Mask_Body {
+  uint8_t bits;
+} Mask_Body;
+
+typedef struct DefineGroup_Body {
+  enum Led leds[MAX_LED];
+  uint8_t name[MAX_GROUP_NAME];
+  uint8_t count;
+  uint8_t name_len;
+} DefineGroup_Body;
+
+typedef struct Fault_Body {
+  uint8_t code;
+} Fault_Body;
+
+typedef struct Cycle_Body {
+  uint16_t step_ms;
+} Cycle_Body;
+
+typedef struct CommandKind {
+  CommandKind_Tag tag;
+  union {
+    Mask_Body mask;
+    DefineGroup_Body define_group;
+    Fault_Body fault;
+    Cycle_Body cycle;
+  };
+} CommandKind;
+
+/**
+ * Parsed command info, returned to the C code.
+ */
 typedef struct Command {
+  /**
+   * Indicates if the parsing was successful. "Option" / "Result" is not FFI friendly.
+   */
   bool success;
+  /**
+   * Which led to operate on.
+   */
   enum Led led;
-  enum LedState state;
+  /**
+   * Which state to put the led in.
+   */
+  struct LedState state;
+  /**
+   * What the command does. The led and state are only meaningful for `CommandKind::Set`.
+   */
+  struct CommandKind kind;
+  /**
+   * How many times the command should be executed, see `repeat_prefix`.
+   * The repetition itself is up to the consumer.
+   */
+  uint8_t repeat;
+  /**
+   * Priority of the command, one of `PRIORITY_NORMAL`, `PRIORITY_LOW` or `PRIORITY_HIGH`.
+   * Ordering the commands is up to the consumer.
+   */
+  uint8_t priority;
+  /**
+   * Sequence number of the command, see `sequence_tag`. 0 if absent.
+   * Deduplication and ordering are up to the consumer.
+   */
+  uint16_t seq;
+  /**
+   * Tick to execute the command at, see `timestamp_tag`. 0 if absent, meaning immediately.
+   * The scheduling is up to the consumer.
+   */
+  uint32_t at_tick;
+  /**
+   * Whether the led keeps its state, false after the "hold" modifier, "esp led1 hold on",
+   * for a press-and-hold control. The momentary behavior is up to the consumer.
+   */
+  bool latched;
+  /**
+   * Address of the node on a multi-drop bus the command is for, "esp@2 led1 on". 0 if
+   * absent, meaning every node. See `parse_with_addr`.
+   */
+  uint8_t addr;
+  /**
+   * Whether the sender wants an acknowledgement, set by the "ack" modifier,
+   * "esp ack led1 on". Sending the ACK frame is up to the consumer.
+   */
+  bool ack_requested;
+  /**
+   * Milliseconds after which an On led turns off again, "esp led1 on for 500". 0 if
+   * absent, meaning the led stays on. The timer is up to the consumer.
+   */
+  uint16_t auto_off_ms;
+  /**
+   * Milliseconds an On led takes to fade in, "esp led1 on fadein 300". 0 if absent,
+   * meaning instantly. The fading is up to the consumer.
+   */
+  uint16_t fade_in_ms;
 } Command;
 
-struct Command parse_uart(const uint8_t *input, uint32_t length);
+
+
+/**
+ * C FFI. Converts the ASCII stream into a usable command.
+ * # To know:
+ * `length` is an upper bound, e.g. the capacity of the serial buffer. The command ends at
+ * the first '\n' or NUL within it, the bytes after the terminator are not looked at.
+ */
+struct Command parse_uart(const uint8_t *input, uintptr_t length);
+
+/**
+ * C FFI. Records the state a led was put in, so that it can be reported by `fill_status`.
+ */
+void set_status(enum Led led, struct LedState state);
+
+/**
+ * C FFI. Answers a status command by writing the recorded state of every led into `out`,
+ * Led1 first. At most `len` states are written, the number of written states is returned.
+ * Nothing is written if `out` is null.
+ */
+uint32_t fill_status(struct LedState *out, uint32_t len);
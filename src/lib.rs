@@ -1,54 +1,551 @@
 #![no_std]
 
-use core::panic::PanicInfo;
-use core::slice::from_raw_parts;
-use nom::{bytes::complete::tag, error::Error, sequence::separated_pair, IResult, combinator::fail};
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
-/// Searches a slice in a slice. If the needle is found in the haystack, the position of the first
-/// matching byte is returned. If no needle is found, None is returned.
-/// Somewhat dubious because .windows can panic if the length of need is 0.
-fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+use core::fmt::{self, Write};
+use core::iter::{Chain, Copied, Enumerate};
+use core::mem::discriminant;
+use core::ops::{Range, RangeFrom, RangeTo};
+use core::slice::{self, from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use nom::character::complete::{self as character, digit1};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till},
+    combinator::{fail, opt},
+    error::Error,
+    sequence::terminated,
+    Compare, CompareResult, IResult, InputIter, InputLength, InputTake, InputTakeAtPosition,
+    Needed, Slice, UnspecializedInput,
+};
+
+/// Input the grammar matches on, a byte slice or a `Wrapped` ring buffer. On top of the nom
+/// input traits, it has the few slice methods the grammar needs.
+trait Input:
+    Copy
+    + InputIter<Item = u8>
+    + InputLength
+    + InputTake
+    + InputTakeAtPosition<Item = u8>
+    + Slice<RangeFrom<usize>>
+    + Slice<RangeTo<usize>>
+    + Slice<Range<usize>>
+    + for<'b> Compare<&'b [u8]>
+{
+    fn len(self) -> usize {
+        self.input_len()
+    }
+
+    fn is_empty(self) -> bool {
+        self.input_len() == 0
+    }
+
+    fn first(self) -> Option<u8> {
+        self.iter_elements().next()
+    }
+
+    fn starts_with(self, prefix: &[u8]) -> bool {
+        matches!(self.compare(prefix), CompareResult::Ok)
+    }
+
+    fn strip_prefix(self, prefix: &[u8]) -> Option<Self> {
+        self.starts_with(prefix).then(|| self.slice(prefix.len()..))
+    }
+}
+
+impl Input for &[u8] {}
+
+/// The two parts of a ring buffer that wrapped around, `front` being the older one. The
+/// grammar matches them as one input without joining them, see `Command::from_slices`.
+#[derive(Clone, Copy)]
+struct Wrapped<'a> {
+    front: &'a [u8],
+    back: &'a [u8],
+}
+
+impl Wrapped<'_> {
+    /// Keeps the bytes from `start` to `end` of both parts. Panics like slicing if the range
+    /// is out of bounds.
+    fn range(&self, start: usize, end: usize) -> Self {
+        let split = self.front.len();
+        Wrapped {
+            front: &self.front[start.min(split)..end.min(split)],
+            back: &self.back[start.saturating_sub(split)..end.saturating_sub(split)],
+        }
+    }
+}
+
+impl Input for Wrapped<'_> {}
+
+impl InputLength for Wrapped<'_> {
+    fn input_len(&self) -> usize {
+        self.front.len() + self.back.len()
+    }
+}
+
+impl InputTake for Wrapped<'_> {
+    fn take(&self, count: usize) -> Self {
+        self.range(0, count)
+    }
+
+    fn take_split(&self, count: usize) -> (Self, Self) {
+        (self.range(count, self.input_len()), self.range(0, count))
+    }
+}
+
+impl<'a> InputIter for Wrapped<'a> {
+    type Item = u8;
+    type Iter = Enumerate<Self::IterElem>;
+    type IterElem = Chain<Copied<slice::Iter<'a, u8>>, Copied<slice::Iter<'a, u8>>>;
+
+    fn iter_indices(&self) -> Self::Iter {
+        self.iter_elements().enumerate()
+    }
+
+    fn iter_elements(&self) -> Self::IterElem {
+        self.front.iter().copied().chain(self.back.iter().copied())
+    }
+
+    fn position<P: Fn(u8) -> bool>(&self, predicate: P) -> Option<usize> {
+        self.iter_elements().position(predicate)
+    }
+
+    fn slice_index(&self, count: usize) -> Result<usize, Needed> {
+        let length = self.input_len();
+        match count <= length {
+            true => Ok(count),
+            false => Err(Needed::new(count - length)),
+        }
+    }
+}
+
+impl Slice<RangeFrom<usize>> for Wrapped<'_> {
+    fn slice(&self, range: RangeFrom<usize>) -> Self {
+        self.range(range.start, self.input_len())
+    }
+}
+
+impl Slice<RangeTo<usize>> for Wrapped<'_> {
+    fn slice(&self, range: RangeTo<usize>) -> Self {
+        self.range(0, range.end)
+    }
+}
+
+impl Slice<Range<usize>> for Wrapped<'_> {
+    fn slice(&self, range: Range<usize>) -> Self {
+        self.range(range.start, range.end)
+    }
+}
+
+// Gives the generic nom `Compare` and `InputTakeAtPosition`.
+impl UnspecializedInput for Wrapped<'_> {}
+
+/// Checks if a token ends at the start of the slice, i.e. the slice is empty or starts with
+/// a space or a control byte, e.g. the ETX after an embedded frame. Used to only accept
+/// clean matches.
+fn is_token_end<I: Input>(input: I) -> bool {
+    input
+        .first()
+        .is_none_or(|byte| byte == b' ' || byte.is_ascii_control())
+}
+
+/// Strips the token from the start of the slice, if it is matched cleanly.
+/// # Example
+/// in:                         out:
+/// input = "on x", token "on"  Some(" x")
+/// input = "onn", token "on"   None
+fn strip_token<I: Input>(input: I, token: &[u8]) -> Option<I> {
+    input.strip_prefix(token).filter(|rest| is_token_end(*rest))
+}
+
+/// Strips a structural keyword from the start of the slice, ignoring the ASCII case.
+/// # To know:
+/// Only the keywords "esp", "led", "on" and "off" are matched this way. Values, like color
+/// names, and the other keywords keep their case.
+/// # Example
+/// in:                           out:
+/// input = "LED1", keyword "led"   Some("1")
+/// input = "le", keyword "led"     None
+fn strip_keyword<I: Input>(input: I, keyword: &[u8]) -> Option<I> {
+    matches!(input.compare_no_case(keyword), CompareResult::Ok)
+        .then(|| input.slice(keyword.len()..))
 }
 
 /// State of an Led.
-#[derive(Debug, PartialEq)]
+/// # To know:
+/// Without the default feature `extended-states` only On, Off and Toggle exist, which keeps
+/// `Command` small for on/off-only firmwares. The C layout follows the feature, the header
+/// has the other states only if `CMD_EXTENDED_STATES` is defined, see cbindgen.toml.
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(C)]
 pub enum LedState {
-    On,
+    /// On with the given brightness, "on 128". Plain "on" is `BRIGHTNESS_FULL`, see
+    /// `LedState::ON`.
+    /// # To know:
+    /// In C this is a tagged struct, the discriminant followed by the brightness byte.
+    /// The size of LedState is unchanged, a larger variant already sets it.
+    On {
+        brightness: u8,
+    },
     Off,
+    /// Flips the led, "toggle". An off led turns on, any other state turns off, see
+    /// `Command::apply`.
+    Toggle,
+    /// Blinks with the given period, "blink 500ms".
+    #[cfg(feature = "extended-states")]
+    Blink {
+        period_ms: u16,
+    },
+    /// Fades to off over the given duration, "fadeout 1000" for a graceful shutdown.
+    #[cfg(feature = "extended-states")]
+    FadeOut {
+        duration_ms: u16,
+    },
+    /// Shows a color, "rgb 255 128 0".
+    #[cfg(feature = "extended-states")]
+    Rgb {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    /// Steps through the brightness values, "ramp 0 64 128 255". Only the first `count`
+    /// values are used, the others are 0.
+    #[cfg(feature = "extended-states")]
+    Ramp {
+        values: [u8; MAX_ARGS],
+        count: u8,
+    },
+    /// Plays the on/off frames of a flash code, "pattern 10110". The first frame is the
+    /// highest of the `len` low bits of `bits`, a set bit is on.
+    #[cfg(feature = "extended-states")]
+    Pattern {
+        bits: u16,
+        len: u8,
+    },
 }
 
+/// Brightness of a led that is turned on without giving one.
+pub const BRIGHTNESS_FULL: u8 = 255;
+
 impl LedState {
-    /// Nom filter function. Checks if the slice contain a LedState.
+    /// On with full brightness, what a plain "on" parses to.
+    pub const ON: LedState = LedState::On {
+        brightness: BRIGHTNESS_FULL,
+    };
+
+    /// The state a toggle leaves the led in, On if it is off and Off otherwise.
+    fn inverted(&self) -> LedState {
+        if *self == LedState::Off {
+            LedState::ON
+        } else {
+            LedState::Off
+        }
+    }
+
+    /// Nom filter function. Checks if the slice starts with a LedState.
     /// # To know:
-    /// This function does not check if the match is clean. This means it
-    /// detects things like: "on" and "off" but also "oonn" or "offasdf"
-    ///                                                ^^       ^^^
+    /// The match must be clean, the state has to be a whole token. Things like
+    /// "oonn" or "offasdf" are rejected.
+    /// The synonyms "enable" / "high" and "disable" / "low", used by other firmwares, are
+    /// accepted for On and Off, see `STATE_TOKENS`. Only "on" and "off" themselves ignore
+    /// the case.
+    /// On may be followed by a brightness from 0 to 255, or a fraction of the full brightness,
+    /// see `brightness`. "brightness" is the same as "on" with a brightness.
+    /// The states carrying values other than a brightness need the feature `extended-states`.
     /// If a state is detected, the input gets split up after the state sequence.
     /// # Example
     /// in:                 out:
     /// input = "on"        Ok("LedState:On", ())
-    /// input = "onnnnn"    Ok("LedState:On", "nnnn")
+    /// input = "ON"        Ok("LedState:On", ())
+    /// input = "on 128"    Ok(LedState::On { brightness: 128 }, ())
+    /// input = "on 1/2"    Ok(LedState::On { brightness: 127 }, ())
+    /// input = "brightness 3/4"    Ok(LedState::On { brightness: 191 }, ())
+    /// input = "off x"     Ok("LedState:Off", " x")
+    /// input = "high"      Ok("LedState:On", ())
+    /// input = "toggle"    Ok("LedState:Toggle", ())
+    /// input = "f"         Ok("LedState:Off", ())
+    /// input = "blink 2s"  Ok(LedState::Blink { period_ms: 2000 }, ())
+    /// input = "rgb 1 2 3" Ok(LedState::Rgb { r: 1, g: 2, b: 3 }, ())
+    /// input = "color red" Ok(LedState::Rgb { r: 255, g: 0, b: 0 }, ())
+    /// input = "fadeout 1000"  Ok(LedState::FadeOut { duration_ms: 1000 }, ())
+    /// input = "ramp 0 255"    Ok(LedState::Ramp { values: [0, 255, 0, 0], count: 2 }, ())
+    /// input = "pattern 10110" Ok(LedState::Pattern { bits: 0b10110, len: 5 }, ())
+    /// input = "onnnnn"    Err("onnnnn")
     /// input = "asdf"      Err("asdf")
-    fn from_slice(input: &[u8]) -> IResult<&[u8], LedState> {
+    /// # Composition
+    /// Can be embedded in a larger nom parser, the signature is stable. See `command_parser`.
+    pub fn from_slice(input: &[u8]) -> IResult<&[u8], LedState> {
+        LedState::from_input(input)
+    }
+
+    /// Same as `from_slice`, on any input of the grammar.
+    fn from_input<I: Input>(input: I) -> IResult<I, LedState> {
         const ON: &[u8] = b"on";
         const OFF: &[u8] = b"off";
+        const BRIGHTNESS: &[u8] = b"brightness ";
+        const SPACE: &[u8] = b" ";
 
-        if let Some(pos) = find_subsequence(input, ON) {
-            return Ok((&input[(pos + ON.len())..], LedState::On));
+        #[cfg(feature = "extended-states")]
+        if let (rest, Some(state)) = LedState::extended_from_slice(input)? {
+            return Ok((rest, state));
         }
-        if let Some(pos) = find_subsequence(input, OFF) {
-            return Ok((&input[(pos + OFF.len())..], LedState::Off));
+        if let Some(rest) = input.strip_prefix(BRIGHTNESS) {
+            let (rest, brightness) = brightness(rest)?;
+            return Ok((rest, LedState::On { brightness }));
         }
-        fail(input)
+        let found = STATE_TOKENS.iter().find_map(|(token, state)| {
+            let rest = if *token == ON || *token == OFF {
+                strip_keyword(input, token).filter(|rest| is_token_end(*rest))
+            } else {
+                strip_token(input, token)
+            };
+            Some((rest?, *state))
+        });
+        match found {
+            Some((rest, LedState::On { .. })) => {
+                // A brightness token with a slash is a fraction, the others are arguments.
+                let fraction = rest.strip_prefix(SPACE).filter(|rest| {
+                    rest.iter_elements()
+                        .take_while(|byte| *byte != b' ')
+                        .any(|byte| byte == b'/')
+                });
+                if let Some(fraction) = fraction {
+                    let Ok((rest, brightness)) = brightness(fraction) else {
+                        return fail(input);
+                    };
+                    return Ok((rest, LedState::On { brightness }));
+                }
+                let (rest, brightness) = match arguments(rest)? {
+                    (rest, (_, 0)) => (rest, BRIGHTNESS_FULL),
+                    (rest, ([brightness, ..], 1)) => (rest, brightness),
+                    _ => return fail(input),
+                };
+                Ok((rest, LedState::On { brightness }))
+            }
+            Some((rest, state)) => Ok((rest, state)),
+            None => fail(input),
+        }
+    }
+
+    /// Nom filter function. Checks if the slice starts with one of the states carrying values
+    /// other than a brightness. Gives None, and leaves the input, if no such state begins it.
+    #[cfg(feature = "extended-states")]
+    fn extended_from_slice<I: Input>(input: I) -> IResult<I, Option<LedState>> {
+        const BLINK: &[u8] = b"blink ";
+        const RGB: &[u8] = b"rgb";
+        const FADE_OUT: &[u8] = b"fadeout ";
+        const RAMP: &[u8] = b"ramp";
+        const COLOR: &[u8] = b"color ";
+        const PATTERN: &[u8] = b"pattern ";
+
+        if let Some(rest) = input.strip_prefix(BLINK) {
+            let (rest, period_ms) = duration_ms(rest)?;
+            return Ok((rest, Some(LedState::Blink { period_ms })));
+        }
+        if let Some(rest) = input.strip_prefix(FADE_OUT) {
+            let (rest, duration_ms) = duration_ms(rest)?;
+            return Ok((rest, Some(LedState::FadeOut { duration_ms })));
+        }
+        if let Some(rest) = input.strip_prefix(RGB) {
+            let (rest, (args, count)) = arguments(rest)?;
+            if count != 3 {
+                return fail(input);
+            }
+            let [r, g, b, ..] = args;
+            return Ok((rest, Some(LedState::Rgb { r, g, b })));
+        }
+        if let Some(rest) = input.strip_prefix(COLOR) {
+            let found = COLORS
+                .iter()
+                .find_map(|(name, rgb)| Some((strip_token(rest, name)?, *rgb)));
+            let Some((rest, [r, g, b])) = found else {
+                return fail(input);
+            };
+            return Ok((rest, Some(LedState::Rgb { r, g, b })));
+        }
+        if let Some(rest) = input.strip_prefix(PATTERN) {
+            let (rest, (bits, len)) = pattern_bits(rest)?;
+            return Ok((rest, Some(LedState::Pattern { bits, len })));
+        }
+        // A ramp longer than `MAX_ARGS` values fails, it is not truncated.
+        if let Some(rest) = input.strip_prefix(RAMP) {
+            let (rest, (values, count)) = arguments(rest)?;
+            if count == 0 {
+                return fail(input);
+            }
+            let count = count as u8;
+            return Ok((rest, Some(LedState::Ramp { values, count })));
+        }
+        Ok((input, None))
+    }
+}
+
+/// The keywords of the plain states, as `LedState::from_slice` matches them. Shared with
+/// host-side validators, so they accept the same tokens as the firmware.
+/// # To know:
+/// The states that carry values, like "blink 500ms", are not listed. On may still be
+/// followed by a brightness. The single letters "n" and "f" are the short forms of On and
+/// Off for tiny links, as whole tokens they do not clash with longer words.
+pub const STATE_TOKENS: [(&[u8], LedState); 9] = [
+    (b"on", LedState::ON),
+    (b"enable", LedState::ON),
+    (b"high", LedState::ON),
+    (b"n", LedState::ON),
+    (b"off", LedState::Off),
+    (b"disable", LedState::Off),
+    (b"low", LedState::Off),
+    (b"f", LedState::Off),
+    (b"toggle", LedState::Toggle),
+];
+
+/// Named colors of the "color" state, e.g. "color red", as r, g, b values.
+#[cfg(feature = "extended-states")]
+const COLORS: [(&[u8], [u8; 3]); 8] = [
+    (b"red", [255, 0, 0]),
+    (b"green", [0, 255, 0]),
+    (b"blue", [0, 0, 255]),
+    (b"white", [255, 255, 255]),
+    (b"yellow", [255, 255, 0]),
+    (b"cyan", [0, 255, 255]),
+    (b"magenta", [255, 0, 255]),
+    (b"black", [0, 0, 0]),
+];
+
+/// Most numeric arguments a state may carry, e.g. the three channels of "rgb 1 2 3".
+/// Bounds the work spent on a single command.
+pub const MAX_ARGS: usize = 4;
+
+/// Nom filter function. Parses the space separated numeric arguments of a state, at most
+/// `MAX_ARGS` of them. Returns the arguments and how many were given.
+/// # To know:
+/// Parsing fails as soon as an argument over the limit is found, the rest of the list is
+/// not looked at. The arguments end at the first token that is not a number. A number over
+/// 255 fails too, it is a wrong value rather than the token after the arguments.
+/// # Example
+/// in:                 out:
+/// input = " 1 2 3"    Ok(([1, 2, 3, 0], 3), ())
+/// input = " 1 2 x"    Ok(([1, 2, 0, 0], 2), " x")
+/// input = " 1 1 1 1 1" Err(" 1 1 1 1 1")
+/// input = " 1 300"    Err(" 1 300")
+fn arguments<I: Input>(input: I) -> IResult<I, ([u8; MAX_ARGS], usize)> {
+    const SPACE: &[u8] = b" ";
+
+    let mut args = [0u8; MAX_ARGS];
+    let mut count = 0;
+    let mut rest = input;
+    while let Some(next) = rest.strip_prefix(SPACE) {
+        let Ok((next, arg)) = character::u8::<_, Error<_>>(next) else {
+            let digits = next
+                .position(|byte| !byte.is_ascii_digit())
+                .unwrap_or(next.len());
+            if digits > 0 && is_token_end(next.slice(digits..)) {
+                return fail(input);
+            }
+            break;
+        };
+        if !is_token_end(next) {
+            break;
+        }
+        if count == MAX_ARGS {
+            return fail(input);
+        }
+        args[count] = arg;
+        count += 1;
+        rest = next;
+    }
+    Ok((rest, (args, count)))
+}
+
+/// Nom filter function. Parses a brightness, either a number from 0 to 255 or a fraction
+/// "N/D" of the full brightness, `N * 255 / D` rounded down.
+/// # To know:
+/// A fraction over 1 or with a denominator of 0 is rejected.
+/// # Example
+/// in:                 out:
+/// input = "128"       Ok(128, ())
+/// input = "1/2"       Ok(127, ())
+/// input = "3/4"       Ok(191, ())
+/// input = "1/0"       Err("1/0")
+/// input = "3/2"       Err("3/2")
+fn brightness<I: Input>(input: I) -> IResult<I, u8> {
+    const SLASH: &[u8] = b"/";
+
+    let (rest, numerator) = character::u32(input)?;
+    let (rest, value) = match rest.strip_prefix(SLASH) {
+        Some(rest) => {
+            let (rest, denominator) = character::u32(rest)?;
+            let value = numerator
+                .checked_mul(u32::from(BRIGHTNESS_FULL))
+                .and_then(|scaled| scaled.checked_div(denominator));
+            (rest, value)
+        }
+        None => (rest, Some(numerator)),
+    };
+    match value.and_then(|value| u8::try_from(value).ok()) {
+        Some(value) if is_token_end(rest) => Ok((rest, value)),
+        _ => fail(input),
+    }
+}
+
+/// Nom filter function. Parses a run of up to 16 binary digits into a bitmask, the first
+/// digit being the highest bit, and the number of digits.
+/// # Example
+/// in:                 out:
+/// input = "10110"     Ok((0b10110, 5), ())
+/// input = "1021"      Err("1021")
+#[cfg(feature = "extended-states")]
+fn pattern_bits<I: Input>(input: I) -> IResult<I, (u16, u8)> {
+    let len = input
+        .position(|byte| !matches!(byte, b'0' | b'1'))
+        .unwrap_or(input.len());
+    if len == 0 || len > u16::BITS as usize || !is_token_end(input.slice(len..)) {
+        return fail(input);
+    }
+    let bits = input
+        .slice(..len)
+        .iter_elements()
+        .fold(0, |bits, digit| (bits << 1) | u16::from(digit - b'0'));
+    Ok((input.slice(len..), (bits, len as u8)))
+}
+
+/// Nom filter function. Parses a duration into milliseconds. The value is a bare
+/// integer in milliseconds or has the unit "ms" or "s", optionally after a space.
+/// # To know:
+/// The unit must end the token, and the duration must fit a u16 after scaling.
+/// A token after the space that is no unit is left in the input.
+/// # Example
+/// in:                 out:
+/// input = "500"       Ok(500, ())
+/// input = "500ms on"  Ok(500, " on")
+/// input = "500 ms"    Ok(500, ())
+/// input = "500 xs"    Ok(500, " xs")
+/// input = "2s"        Ok(2000, ())
+/// input = "70s"       Err("70s")
+/// input = "s"         Err("s")
+fn duration_ms<I: Input>(input: I) -> IResult<I, u16> {
+    const MILLISECONDS: &[u8] = b"ms";
+    const SECONDS: &[u8] = b"s";
+
+    let (rest, value) = character::u16(input)?;
+    let unit_input = rest.strip_prefix(b" ").unwrap_or(rest);
+    let (unit_rest, unit) = opt(alt((tag(MILLISECONDS), tag(SECONDS))))(unit_input)?;
+    let (rest, scale) = match unit {
+        Some(unit) if is_token_end(unit_rest) => {
+            (unit_rest, if unit.starts_with(SECONDS) { 1000 } else { 1 })
+        }
+        _ => (rest, 1),
+    };
+    match value.checked_mul(scale) {
+        Some(duration) if is_token_end(rest) => Ok((rest, duration)),
+        _ => fail(input),
     }
 }
 
 /// Represents the four led's on the board.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(C)]
 pub enum Led {
     Led1,
@@ -57,42 +554,120 @@ pub enum Led {
     Led4,
 }
 
+/// Number of leds on the board.
+pub const MAX_LED: u8 = 4;
+
+/// The canonical form of every led, Led1 first, as written by `Command::to_bytes`. Shared
+/// with host-side validators. `Led::from_slice` also accepts the other forms, e.g. "l1".
+pub const LED_TOKENS: [(&[u8], Led); MAX_LED as usize] = [
+    (b"led1", Led::Led1),
+    (b"led2", Led::Led2),
+    (b"led3", Led::Led3),
+    (b"led4", Led::Led4),
+];
+
+/// Number of a led, from 1 to `MAX_LED` as written on the board and in the commands.
+/// Keeps led numbers from being mixed up with other raw bytes, e.g. brightness values.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(transparent)]
+pub struct LedIndex(u8);
+
+impl LedIndex {
+    /// Returns the index if it is in the range 1 to `MAX_LED`.
+    pub fn new(index: u8) -> Option<LedIndex> {
+        (1..=MAX_LED).contains(&index).then_some(LedIndex(index))
+    }
+
+    /// Returns the led number, from 1 to `MAX_LED`.
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
 impl Led {
-    /// Nom filter function. Checks if the slice contains any led.
+    /// Returns the led with the given number.
+    pub fn from_index(index: LedIndex) -> Led {
+        match index.get() {
+            1 => Led::Led1,
+            2 => Led::Led2,
+            3 => Led::Led3,
+            _ => Led::Led4,
+        }
+    }
+
+    /// Returns the number of the led.
+    pub fn index(&self) -> LedIndex {
+        LedIndex(*self as u8 + 1)
+    }
+
+    /// Returns the led after this one, Led4 wraps around to Led1.
+    pub fn next(&self) -> Led {
+        Led::from_index(LedIndex(self.index().get() % MAX_LED + 1))
+    }
+
+    /// Returns the led before this one, Led1 wraps around to Led4.
+    pub fn prev(&self) -> Led {
+        Led::from_index(LedIndex((self.index().get() + MAX_LED - 2) % MAX_LED + 1))
+    }
+
+    /// Returns the canonical form of the led in a command, e.g. "led1", as written by
+    /// `Command::to_bytes`. See `LED_TOKENS`.
+    pub const fn token(&self) -> &'static [u8] {
+        LED_TOKENS[*self as usize].0
+    }
+
+    /// Converts an ASCII digit ('1' to '4') into the matching led.
+    fn from_digit(digit: u8) -> Option<Led> {
+        LedIndex::new(digit.wrapping_sub(b'0')).map(Led::from_index)
+    }
+
+    /// Nom filter function. Checks if the slice starts with a led.
+    /// A led is written as "led" or the short form "l", followed by its digit.
+    /// The full form may have a space before the digit, "led 2", and ignores the case, "LED2".
     /// # To know:
-    /// This function does not check if the match is clean. This means it
-    /// detects things like: "led1" and "led2" but also "lled11" or "led2asdf"
-    ///                                                   ^^^        ^^^
-    /// If a state is detected, the input gets split up after the state sequence.
+    /// The match must be clean, the led has to be a whole token. Things like
+    /// "lled11" or "led2asdf" are rejected.
+    /// If a led is detected, the input gets split up after the led sequence.
     /// # Example
     /// in:                 out:
     /// input = "led1"      Ok(Led::Led1, ())
-    /// input = "asled2df"  Ok(Led::Led2, "df")
-    /// input = "asdf"      Err("asdf")
-    fn from_slice(input: &[u8]) -> IResult<&[u8], Led> {
-        const LED1: &[u8] = b"led1";
-        const LED2: &[u8] = b"led2";
-        const LED3: &[u8] = b"led3";
-        const LED4: &[u8] = b"led4";
+    /// input = "l3 on"     Ok(Led::Led3, " on")
+    /// input = "led 4"     Ok(Led::Led4, ())
+    /// input = "asled2df"  Err("asled2df")
+    /// input = "led5"      Err("led5")
+    /// # Composition
+    /// Can be embedded in a larger nom parser, the signature is stable. See `command_parser`.
+    pub fn from_slice(input: &[u8]) -> IResult<&[u8], Led> {
+        Led::from_input(input)
+    }
 
-        if let Some(pos) = find_subsequence(input, LED1) {
-            return Ok((&input[(pos + LED1.len())..], Led::Led1));
-        }
-        if let Some(pos) = find_subsequence(input, LED2) {
-            return Ok((&input[(pos + LED2.len())..], Led::Led2));
-        }
-        if let Some(pos) = find_subsequence(input, LED3) {
-            return Ok((&input[(pos + LED3.len())..], Led::Led3));
-        }
-        if let Some(pos) = find_subsequence(input, LED4) {
-            return Ok((&input[(pos + LED4.len())..], Led::Led4));
+    /// Same as `from_slice`, on any input of the grammar.
+    fn from_input<I: Input>(input: I) -> IResult<I, Led> {
+        const LED: &[u8] = b"led";
+        const SHORT: &[u8] = b"l";
+        const SPACE: &[u8] = b" ";
+
+        // The full keyword goes first, so "led1" is not looked at as a short form.
+        let rest = match strip_keyword(input, LED) {
+            Some(rest) => rest.strip_prefix(SPACE).unwrap_or(rest),
+            None => match input.strip_prefix(SHORT) {
+                Some(rest) => rest,
+                None => return fail(input),
+            },
+        };
+        let Some(digit) = rest.first() else {
+            return fail(input);
+        };
+        let rest = rest.slice(1..);
+        match Led::from_digit(digit) {
+            Some(led) if is_token_end(rest) => Ok((rest, led)),
+            _ => fail(input),
         }
-        fail(input)
     }
 }
 
 /// Parsed command info, returned to the C code.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(C)]
 pub struct Command {
     /// Indicates if the parsing was successful. "Option" / "Result" is not FFI friendly.
@@ -101,93 +676,5550 @@ pub struct Command {
     pub led: Led,
     /// Which state to put the led in.
     pub state: LedState,
+    /// What the command does. The led and state are only meaningful for `CommandKind::Set`.
+    pub kind: CommandKind,
+    /// How many times the command should be executed, see `repeat_prefix`.
+    /// The repetition itself is up to the consumer.
+    pub repeat: u8,
+    /// Priority of the command, one of `PRIORITY_NORMAL`, `PRIORITY_LOW` or `PRIORITY_HIGH`.
+    /// Ordering the commands is up to the consumer.
+    pub priority: u8,
+    /// Sequence number of the command, see `sequence_tag`. 0 if absent.
+    /// Deduplication and ordering are up to the consumer.
+    pub seq: u16,
+    /// Tick to execute the command at, see `timestamp_tag`. 0 if absent, meaning immediately.
+    /// The scheduling is up to the consumer.
+    pub at_tick: u32,
+    /// Whether the led keeps its state, false after the "hold" modifier, "esp led1 hold on",
+    /// for a press-and-hold control. The momentary behavior is up to the consumer.
+    pub latched: bool,
+    /// Address of the node on a multi-drop bus the command is for, "esp@2 led1 on". 0 if
+    /// absent, meaning every node. See `parse_with_addr`.
+    pub addr: u8,
+    /// Whether the sender wants an acknowledgement, set by the "ack" modifier,
+    /// "esp ack led1 on". Sending the ACK frame is up to the consumer.
+    pub ack_requested: bool,
+    /// Milliseconds after which an On led turns off again, "esp led1 on for 500". 0 if
+    /// absent, meaning the led stays on. The timer is up to the consumer.
+    pub auto_off_ms: u16,
+    /// Milliseconds an On led takes to fade in, "esp led1 on fadein 300". 0 if absent,
+    /// meaning instantly. The fading is up to the consumer.
+    pub fade_in_ms: u16,
+}
+
+/// The different kinds of commands.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub enum CommandKind {
+    /// Puts a led in a state, e.g. "esp led1 on".
+    Set,
+    /// Asks for the state of all the leds, "esp status". See `fill_status`.
+    Status,
+    /// Turns every led off, "esp clear". Also the kind of `Command::safe_default`.
+    /// Unlike a reset, which would restore the board's default states, the leds end up off
+    /// whatever their defaults are.
+    Clear,
+    /// Asks the board to identify itself on a shared bus, "esp whoami". The firmware blinks
+    /// a pattern and reports its address.
+    Identify,
+    /// Does nothing, "esp noop" or "esp ping". Keep-alive frames that the firmware
+    /// acknowledges without changing the leds.
+    Noop,
+    /// Turns the leds on or off at once, "esp mask 5". Bit 0 is Led1, bit 1 Led2 and so on,
+    /// 5 turns Led1 and Led3 on and the others off. See `Command::apply`.
+    /// Carrying the bits makes CommandKind a tagged struct in C, the kind is `kind.tag`.
+    Mask { bits: u8 },
+    /// Toggles every led, "esp invert", for a quick visual test. See `Command::apply`.
+    InvertAll,
+    /// Defines a group of leds at runtime, "esp def front led1 led2". Only the first
+    /// `count` leds and `name_len` bytes of the name are used, the others are Led1 and 0.
+    /// Storing the group and expanding it, see `parse_group`, is up to the firmware.
+    DefineGroup {
+        leds: [Led; MAX_LED as usize],
+        name: [u8; MAX_GROUP_NAME],
+        count: u8,
+        name_len: u8,
+    },
+    /// Tells the firmware to simulate a fault condition, "esp fault 3", for fault testing.
+    /// Only test builds of the firmware act on it, the parser accepts it everywhere.
+    Fault { code: u8 },
+    /// Asks the firmware to list the supported commands, a bare "esp" typed by an operator.
+    /// With a space, "esp " fails as `ParseError::EmptyCommand`.
+    Help,
+    /// Lights Led1 to Led4 in turn, each for `step_ms` and then off again, "esp cycle 200".
+    /// All leds are off when it is done.
+    Cycle { step_ms: u16 },
+}
+
+/// Longest name of a group defined with "esp def", in bytes.
+pub const MAX_GROUP_NAME: usize = 8;
+
+impl CommandKind {
+    /// Checks if the slice starts with the keyword of a command that takes no led or state.
+    /// The keyword must be a whole token, see `is_token_end`, the input gets split up after it.
+    /// # Example
+    /// in:                 out:
+    /// input = "status"    Some((), CommandKind::Status)
+    /// input = "status x"  Some(" x", CommandKind::Status)
+    /// input = "status\r"  Some("\r", CommandKind::Status)
+    /// input = "statusx"   None
+    fn from_keyword<I: Input>(input: I) -> Option<(I, CommandKind)> {
+        const KEYWORDS: [(&[u8], CommandKind); 6] = [
+            (b"status", CommandKind::Status),
+            (b"clear", CommandKind::Clear),
+            (b"whoami", CommandKind::Identify),
+            (b"noop", CommandKind::Noop),
+            (b"ping", CommandKind::Noop),
+            (b"invert", CommandKind::InvertAll),
+        ];
+
+        KEYWORDS
+            .iter()
+            .find_map(|(keyword, kind)| Some((strip_token(input, keyword)?, *kind)))
+    }
+
+    /// Returns the keyword of a command that takes no led or state.
+    fn keyword(&self) -> Option<&'static [u8]> {
+        match self {
+            CommandKind::Set | CommandKind::Help => None,
+            CommandKind::Status => Some(b"status"),
+            CommandKind::Clear => Some(b"clear"),
+            CommandKind::Identify => Some(b"whoami"),
+            CommandKind::Noop => Some(b"noop"),
+            CommandKind::Mask { .. } => Some(b"mask"),
+            CommandKind::InvertAll => Some(b"invert"),
+            CommandKind::DefineGroup { .. } => Some(b"def"),
+            CommandKind::Fault { .. } => Some(b"fault"),
+            CommandKind::Cycle { .. } => Some(b"cycle"),
+        }
+    }
+
+    /// Code of the kind in `Command::opcode`, its C enum value.
+    fn code(&self) -> u16 {
+        match self {
+            CommandKind::Set => 0,
+            CommandKind::Status => 1,
+            CommandKind::Clear => 2,
+            CommandKind::Identify => 3,
+            CommandKind::Noop => 4,
+            CommandKind::Mask { .. } => 5,
+            CommandKind::InvertAll => 6,
+            CommandKind::DefineGroup { .. } => 7,
+            CommandKind::Fault { .. } => 8,
+            CommandKind::Help => 9,
+            CommandKind::Cycle { .. } => 10,
+        }
+    }
+
+    /// Returns the name and the leds of a group definition, in the form `parse_group` takes.
+    pub fn group(&self) -> Option<(&[u8], &[Led])> {
+        match self {
+            CommandKind::DefineGroup {
+                leds,
+                name,
+                count,
+                name_len,
+            } => Some((&name[..*name_len as usize], &leds[..*count as usize])),
+            _ => None,
+        }
+    }
+}
+
+/// What a command does, as a Rust enum where every variant only holds the data it needs.
+/// `Command` is the flat FFI form of it, the modifiers (repeat, priority, sequence number)
+/// are only part of the latter.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParsedCommand {
+    /// See `CommandKind::Set`.
+    Set { led: Led, state: LedState },
+    /// See `CommandKind::Status`.
+    Status,
+    /// See `CommandKind::Clear`.
+    Clear,
+    /// See `CommandKind::Identify`.
+    Identify,
+    /// See `CommandKind::Noop`.
+    Noop,
+    /// See `CommandKind::Mask`.
+    Mask { bits: u8 },
+    /// See `CommandKind::InvertAll`.
+    InvertAll,
+    /// See `CommandKind::DefineGroup`.
+    DefineGroup {
+        leds: [Led; MAX_LED as usize],
+        name: [u8; MAX_GROUP_NAME],
+        count: u8,
+        name_len: u8,
+    },
+    /// See `CommandKind::Fault`.
+    Fault { code: u8 },
+    /// See `CommandKind::Help`.
+    Help,
+    /// See `CommandKind::Cycle`.
+    Cycle { step_ms: u16 },
+}
+
+impl ParsedCommand {
+    /// Reconstructs the command from its FFI form, e.g. a `Command` stored by the C code.
+    /// Returns None if the parsing of the command was not successful.
+    pub fn from_ffi(cmd: &Command) -> Option<ParsedCommand> {
+        if !cmd.success {
+            return None;
+        }
+        Some(match cmd.kind {
+            CommandKind::Set => ParsedCommand::Set {
+                led: cmd.led,
+                state: cmd.state,
+            },
+            CommandKind::Status => ParsedCommand::Status,
+            CommandKind::Clear => ParsedCommand::Clear,
+            CommandKind::Identify => ParsedCommand::Identify,
+            CommandKind::Noop => ParsedCommand::Noop,
+            CommandKind::Mask { bits } => ParsedCommand::Mask { bits },
+            CommandKind::InvertAll => ParsedCommand::InvertAll,
+            CommandKind::DefineGroup {
+                leds,
+                name,
+                count,
+                name_len,
+            } => ParsedCommand::DefineGroup {
+                leds,
+                name,
+                count,
+                name_len,
+            },
+            CommandKind::Fault { code } => ParsedCommand::Fault { code },
+            CommandKind::Help => ParsedCommand::Help,
+            CommandKind::Cycle { step_ms } => ParsedCommand::Cycle { step_ms },
+        })
+    }
+
+    /// Returns the successful FFI form of the command, without modifiers.
+    pub fn to_ffi(&self) -> Command {
+        let template = CommandBuilder::new().build();
+        match *self {
+            ParsedCommand::Set { led, state } => Command {
+                led,
+                state,
+                ..template
+            },
+            ParsedCommand::Status => Command {
+                kind: CommandKind::Status,
+                ..template
+            },
+            ParsedCommand::Clear => Command {
+                kind: CommandKind::Clear,
+                ..template
+            },
+            ParsedCommand::Identify => Command {
+                kind: CommandKind::Identify,
+                ..template
+            },
+            ParsedCommand::Noop => Command {
+                kind: CommandKind::Noop,
+                ..template
+            },
+            ParsedCommand::Mask { bits } => Command {
+                kind: CommandKind::Mask { bits },
+                ..template
+            },
+            ParsedCommand::InvertAll => Command {
+                kind: CommandKind::InvertAll,
+                ..template
+            },
+            ParsedCommand::DefineGroup {
+                leds,
+                name,
+                count,
+                name_len,
+            } => Command {
+                kind: CommandKind::DefineGroup {
+                    leds,
+                    name,
+                    count,
+                    name_len,
+                },
+                ..template
+            },
+            ParsedCommand::Fault { code } => Command {
+                kind: CommandKind::Fault { code },
+                ..template
+            },
+            ParsedCommand::Help => Command {
+                kind: CommandKind::Help,
+                ..template
+            },
+            ParsedCommand::Cycle { step_ms } => Command {
+                kind: CommandKind::Cycle { step_ms },
+                ..template
+            },
+        }
+    }
 }
 
 impl Command {
     /// Generate a command from a byte slice.
     fn from_slice(input: &[u8]) -> Self {
+        Command::from_input(input)
+    }
+
+    /// Same as `from_slice`, on any input of the grammar.
+    fn from_input<I: Input>(input: I) -> Self {
+        let result = parse_complete(input, &BoardConfig::DEFAULT, None);
+        #[cfg(feature = "metrics")]
+        metrics::record(result.is_ok());
         // Per default, the parsing fails.
-        let mut command = Command {
-            success: false,
-            led: Led::Led1,
-            state: LedState::Off,
-        };
+        result.unwrap_or_default()
+    }
+
+    /// Same as `from_slice`, but overwrites this command in place instead of returning a new
+    /// one, for tight polling loops. Returns whether the parsing was successful.
+    /// On failure, the command becomes `Command::safe_default`, no field of a previous
+    /// command is left behind.
+    pub fn parse_into(&mut self, input: &[u8]) -> bool {
+        *self = Command::from_slice(input);
+        self.success
+    }
+
+    /// Checks if the input is a well-formed command, e.g. for a config linter on the host.
+    /// Runs the same grammar as `parse`, only the reason of a failure is kept.
+    pub fn validate(input: &[u8]) -> Result<(), ParseError> {
+        parse(input).map(|_| ())
+    }
+
+    /// Returns the kind of the command without parsing it, for a cheap pre-dispatch.
+    /// Only the keyword is matched, the modifiers, the state and anything else after the
+    /// keyword are not validated. Returns None without the prefix "esp " or if the keyword
+    /// is neither a command nor a led. The bits of a mask, the code of a fault and the step
+    /// of a cycle are not parsed, they are 0, and a group definition is empty.
+    /// # Example
+    /// in:                     out:
+    /// input = "esp led1 wfea" Some(CommandKind::Set)
+    /// input = "esp 0x status" Some(CommandKind::Status)
+    /// input = "esp"           Some(CommandKind::Help)
+    /// input = "esp wibble"    None
+    pub fn classify(input: &[u8]) -> Option<CommandKind> {
         const ESP: &[u8] = b"esp ";
-        const SPACE: &[u8] = b" ";
 
-        // Check if the command starts with the keyword "esp"
-        if let Ok((input, _)) = tag::<&[u8], &[u8], Error<_>>(ESP)(input) {
-            // Extract the LED and state.
-            if let Ok((_input, (led, state))) = separated_pair(Led::from_slice, tag(SPACE), LedState::from_slice)(input) {
-                command.state = state;
-                command.led = led;
-                command.success = true;
-            };
+        if is_help(input, ESP) {
+            return Some(CommandKind::Help);
+        }
+        let mut input = match address_prefix(input, ESP).ok()? {
+            Some((input, _)) => input,
+            None => strip_keyword(input, ESP)?,
         };
-        command
+        // Skips everything that looks like a modifier, "3x", "!hi", "#42", "@1000" or "ack".
+        while matches!(input.first(), Some(b'0'..=b'9' | b'!' | b'#' | b'@'))
+            || input.starts_with(b"ack ")
+        {
+            let end = input.iter().position(|byte| *byte == b' ')?;
+            input = &input[(end + 1)..];
+        }
+        if let Some((_, kind)) = CommandKind::from_keyword(input) {
+            return Some(kind);
+        }
+        if strip_token(input, b"mask").is_some() {
+            return Some(CommandKind::Mask { bits: 0 });
+        }
+        if strip_token(input, b"fault").is_some() {
+            return Some(CommandKind::Fault { code: 0 });
+        }
+        if strip_token(input, b"cycle").is_some() {
+            return Some(CommandKind::Cycle { step_ms: 0 });
+        }
+        if strip_token(input, b"def").is_some() {
+            return Some(CommandKind::DefineGroup {
+                leds: [Led::Led1; MAX_LED as usize],
+                name: [0; MAX_GROUP_NAME],
+                count: 0,
+                name_len: 0,
+            });
+        }
+        Led::from_slice(input).ok().map(|_| CommandKind::Set)
     }
-}
 
-/// Unsafe function that converts a pointer of bytes into a byte slice.
-/// Needed because slices are not FFI friendly. Potentially dangerous if
-/// a wrong length is give, but that must be handled on the C side.
-fn bytes_to_slice(input: *const u8, length: usize) -> &'static [u8] {
-    unsafe { from_raw_parts(input, length) }
-}
+    /// Splits the input into its words and tells what each of them is recognized as, to look
+    /// at the grammar from tooling. Writes the tokens into `out` and returns their number,
+    /// the words that do not fit are dropped.
+    /// # To know:
+    /// The words are only recognized by their position and spelling, the command itself is
+    /// not validated. Use `parse` for that.
+    /// # Example
+    /// input = "esp led1 blink 500"
+    /// out: [Prefix "esp", Led "led1", State "blink", Argument "500"]
+    pub fn tokenize<'a>(input: &'a [u8], out: &mut [Token<'a>]) -> usize {
+        const STATES: [&[u8]; 7] = [
+            b"blink",
+            b"fadeout",
+            b"rgb",
+            b"ramp",
+            b"color",
+            b"pattern",
+            b"brightness",
+        ];
+        const KEYWORDS: [&[u8]; 4] = [b"mask", b"fault", b"def", b"cycle"];
 
-/// C FFI. Converts the ASCII stream into a usable command.
-#[no_mangle]
-pub extern "C" fn parse_uart(input: *const u8, length: usize) -> Command {
-    Command::from_slice(bytes_to_slice(input, length))
-}
+        let words = trim(input)
+            .split(|byte| *byte == b' ')
+            .filter(|word| !word.is_empty());
+        // What the previous words were recognized as, the led or keyword and the state.
+        let mut target = None;
+        let mut state = false;
+        let mut count = 0;
+        for (slot, text) in out.iter_mut().zip(words) {
+            let kind = if count == 0 {
+                match strip_keyword(text, b"esp") {
+                    Some(rest) if rest.is_empty() || rest.starts_with(b"@") => TokenKind::Prefix,
+                    _ => TokenKind::Unknown,
+                }
+            } else if state || target == Some(TokenKind::Keyword) {
+                TokenKind::Argument
+            } else if target == Some(TokenKind::Led) {
+                if text == b"hold" {
+                    TokenKind::Modifier
+                } else if STATES.contains(&text) || LedState::from_slice(text).is_ok() {
+                    state = true;
+                    TokenKind::State
+                } else {
+                    TokenKind::Unknown
+                }
+            } else if matches!(text.first(), Some(b'0'..=b'9' | b'!' | b'#' | b'@'))
+                || text == b"ack"
+            {
+                TokenKind::Modifier
+            } else if KEYWORDS.contains(&text) || CommandKind::from_keyword(text).is_some() {
+                target = Some(TokenKind::Keyword);
+                TokenKind::Keyword
+            } else if Led::from_slice(text).is_ok() {
+                target = Some(TokenKind::Led);
+                TokenKind::Led
+            } else {
+                TokenKind::Unknown
+            };
+            *slot = Token { kind, text };
+            count += 1;
+        }
+        count
+    }
 
-/// Not sure how to handle a panic.
-#[cfg_attr(not(test), panic_handler)]
-fn panic_handler(_info: &PanicInfo) -> ! {
-    loop {}
-}
+    /// The command to apply when parsing fails, turns everything off.
+    /// It is a failed command (`success` is false) of the kind `CommandKind::Clear`, so it
+    /// can neither be mistaken for a command on a led nor for a successful command.
+    pub const fn safe_default() -> Self {
+        Command {
+            success: false,
+            led: Led::Led1,
+            state: LedState::Off,
+            kind: CommandKind::Clear,
+            repeat: 1,
+            priority: PRIORITY_NORMAL,
+            seq: 0,
+            at_tick: 0,
+            latched: true,
+            addr: 0,
+            ack_requested: false,
+            auto_off_ms: 0,
+            fade_in_ms: 0,
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::*;
+    /// Generate a command from a frame wrapped in STX (0x02) and ETX (0x03) bytes,
+    /// as sent by the physical layer. The command fails if a framing byte is missing.
+    /// # Example
+    /// in:                             out:
+    /// input = "\x02esp led1 on\x03"   Command(Led1, On)
+    /// input = "\x02esp led1 on"       Command(success: false)
+    pub fn from_framed(input: &[u8]) -> Self {
+        const STX: &[u8] = &[0x02];
+        const ETX: &[u8] = &[0x03];
 
-    #[test]
-    fn test_led1_on() {
-        const LED1_ON_COMMAND: &str = "esp led1 on";
-        assert_eq!(
-            parse_uart(LED1_ON_COMMAND.as_ptr(), LED1_ON_COMMAND.len()),
-            Command {
-                success: true,
-                led: Led::Led1,
-                state: LedState::On,
-            }
-        );
+        let inner = input
+            .strip_prefix(STX)
+            .and_then(|input| input.strip_suffix(ETX));
+        // Without its framing, the input fails like any other malformed command.
+        Command::from_slice(inner.unwrap_or_default())
     }
 
-    #[test]
-    fn test_led2_off() {
-        const LED2_OFF_COMMAND: &str = "esp led2 off";
-        assert_eq!(
-            parse_uart(LED2_OFF_COMMAND.as_ptr(), LED2_OFF_COMMAND.len()),
-            Command {
-                success: true,
-                led: Led::Led2,
-                state: LedState::Off,
+    /// Generate a command from the two parts of a ring buffer that wrapped around, `first`
+    /// being the older part. Tokens may straddle the boundary.
+    /// # To know:
+    /// The parts are not joined, the grammar matches across the boundary, see `Wrapped`.
+    /// The limits of `parse` apply to both parts together.
+    /// # Example
+    /// in:                             out:
+    /// first = "esp le", second = "d1 on"  Command(Led1, On)
+    pub fn from_slices(first: &[u8], second: &[u8]) -> Self {
+        Command::from_input(Wrapped {
+            front: first,
+            back: second,
+        })
+    }
+
+    /// Generate a command from its ASCII hex encoding, for transports that cannot carry
+    /// the command as text. The decoded bytes are parsed normally.
+    /// The command fails on an odd length, a non-hex digit or if the decoded command is
+    /// longer than `MAX_FRAME_LEN`.
+    /// # Example
+    /// in:                                 out:
+    /// input = "657370206c656431206f6e"    Command(Led1, On), "esp led1 on"
+    /// input = "657"                       Command(success: false)
+    pub fn from_hex(input: &[u8]) -> Self {
+        /// Value of a hex digit, either case.
+        fn nibble(digit: u8) -> Option<u8> {
+            match digit {
+                b'0'..=b'9' => Some(digit - b'0'),
+                b'a'..=b'f' => Some(digit - b'a' + 10),
+                b'A'..=b'F' => Some(digit - b'A' + 10),
+                _ => None,
             }
-        );
+        }
+
+        let length = input.len() / 2;
+        if !input.len().is_multiple_of(2) || length > MAX_FRAME_LEN {
+            return Command::safe_default();
+        }
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        for (byte, pair) in frame.iter_mut().zip(input.chunks_exact(2)) {
+            match (nibble(pair[0]), nibble(pair[1])) {
+                (Some(high), Some(low)) => *byte = (high << 4) | low,
+                _ => return Command::safe_default(),
+            }
+        }
+        Command::from_slice(&frame[..length])
     }
 
-    #[test]
-    fn test_led2_off_fail() {
-        const LED2_FAIL_COMMAND: &str = "esp led2 wfea";
-        assert_eq!(
-            parse_uart(LED2_FAIL_COMMAND.as_ptr(), LED2_FAIL_COMMAND.len()),
-            Command {
-                success: false,
-                led: Led::Led1,
-                state: LedState::Off,
+    /// Collapses a batch of commands so that every led is only written once: of the
+    /// successful `CommandKind::Set` commands on the same led, only the last one is kept.
+    /// The kept commands are moved to the front of the slice in their original order, the
+    /// new length is returned. Other commands are always kept.
+    /// # Example
+    /// in:                                                 out:
+    /// [Command(Led1, On), Command(Led2, On), Command(Led1, Off)]
+    ///                                                     2, [Command(Led2, On), Command(Led1, Off)]
+    pub fn coalesce(cmds: &mut [Command]) -> usize {
+        let is_set = |command: &Command| command.success && command.kind == CommandKind::Set;
+        let mut length = 0;
+        for i in 0..cmds.len() {
+            let command = cmds[i];
+            let overridden = is_set(&command)
+                && cmds[(i + 1)..]
+                    .iter()
+                    .any(|later| is_set(later) && later.led == command.led);
+            if !overridden {
+                cmds[length] = command;
+                length += 1;
             }
+        }
+        length
+    }
+
+    /// Checks if a receive buffer holds at least one complete command, i.e. a `\n` was
+    /// received. The opposite of `is_partial`, for consumers that only parse full buffers.
+    /// # Example
+    /// in:                             out:
+    /// input = "esp led1 on\nesp le"   true
+    /// input = "esp led1 on"           false
+    pub fn has_complete(input: &[u8]) -> bool {
+        !is_partial(input)
+    }
+
+    /// Splits a buffer holding several commands into their segments without parsing them,
+    /// with the same rules as `CommandStream`: separated by `\n` or `;`, trimmed, empty
+    /// segments and comment lines skipped. The segments are subslices of the input.
+    /// # Example
+    /// input = "esp led1 on;; esp led2 off\n"
+    /// yields: "esp led1 on", "esp led2 off"
+    pub fn segments(input: &[u8]) -> impl Iterator<Item = &[u8]> {
+        Segments::new(input)
+    }
+
+    /// Parses the next command of a buffer holding several, starting at `cursor`, and moves
+    /// the cursor past it and its separator. Returns None once only blanks and comments are
+    /// left. For loops without an iterator, e.g. in an interrupt handler, the segments are
+    /// the same as the ones of `CommandStream`.
+    /// # To know:
+    /// A cursor right after a ';' continues the line, a '#' there is no comment.
+    /// # Example
+    /// input = "esp led1 on; esp led2 off", cursor = 0
+    /// gives Command(Led1, On), cursor = 12, then Command(Led2, Off), cursor = 25, then None
+    pub fn parse_at(input: &[u8], cursor: &mut usize) -> Option<Command> {
+        let rest = input.get(*cursor..)?;
+        let mut segments = Segments {
+            input: rest,
+            at_line_start: *cursor == 0 || input[*cursor - 1] != b';',
+        };
+        let segment = segments.next();
+        *cursor = input.len() - segments.input.len();
+        segment.map(Command::from_slice)
+    }
+
+    /// Applies the command to the states of the leds, Led1 first. Set commands change the
+    /// state of their led, `CommandKind::Clear` (and so a failed command) turns every led
+    /// off and `CommandKind::Mask` turns every led on or off. `CommandKind::InvertAll` turns
+    /// the leds that are off on and all the others off, a toggle does the same for its led.
+    /// Other commands change nothing.
+    pub fn apply(&self, states: &mut [LedState; MAX_LED as usize]) {
+        match self.kind {
+            CommandKind::Set if self.state == LedState::Toggle => {
+                let state = &mut states[self.led as usize];
+                *state = state.inverted();
+            }
+            CommandKind::Set => states[self.led as usize] = self.state,
+            CommandKind::Clear | CommandKind::Cycle { .. } => {
+                *states = [LedState::Off; MAX_LED as usize]
+            }
+            CommandKind::Mask { bits } => {
+                for (i, state) in states.iter_mut().enumerate() {
+                    *state = if bits >> i & 1 == 1 {
+                        LedState::ON
+                    } else {
+                        LedState::Off
+                    };
+                }
+            }
+            CommandKind::InvertAll => {
+                for state in states.iter_mut() {
+                    *state = state.inverted();
+                }
+            }
+            CommandKind::Status
+            | CommandKind::Identify
+            | CommandKind::Noop
+            | CommandKind::DefineGroup { .. }
+            | CommandKind::Fault { .. }
+            | CommandKind::Help => (),
+        }
+    }
+
+    /// Returns the leds the command changes as a bitmask, bit 0 being Led1, like
+    /// `CommandKind::Mask`. The mask matches what `apply` changes, so a list like
+    /// "esp led1,led2 on" is applied in one masked loop by joining its masks.
+    /// # Example
+    /// in:                     out:
+    /// Command(Led3, On)       0b0100
+    /// Command(Clear)          0b1111
+    /// Command(Status)         0b0000
+    pub fn target_mask(&self) -> u8 {
+        const ALL: u8 = (1 << MAX_LED) - 1;
+
+        match self.kind {
+            CommandKind::Set => 1 << self.led as u8,
+            CommandKind::Clear
+            | CommandKind::Mask { .. }
+            | CommandKind::InvertAll
+            | CommandKind::Cycle { .. } => ALL,
+            CommandKind::Status
+            | CommandKind::Identify
+            | CommandKind::Noop
+            | CommandKind::DefineGroup { .. }
+            | CommandKind::Fault { .. }
+            | CommandKind::Help => 0,
+        }
+    }
+
+    /// Tells which parts of the command differ from `other`, for change-detection telemetry:
+    /// `DIFF_LED`, `DIFF_STATE` or `DIFF_PAYLOAD`, or 0 if none. A changed state is never
+    /// reported as a changed payload as well. The kind and the modifiers are not compared.
+    /// # Example
+    /// in:                                         out:
+    /// Command(Led1, On), Command(Led2, On)        DIFF_LED
+    /// Command(Led1, On), Command(Led1, Off)       DIFF_STATE
+    /// Command(Led1, On), Command(Led1, On(128))   DIFF_PAYLOAD
+    pub fn diff(&self, other: &Command) -> u8 {
+        let mut bits = 0;
+        if self.led != other.led {
+            bits |= DIFF_LED;
+        }
+        if discriminant(&self.state) != discriminant(&other.state) {
+            bits |= DIFF_STATE;
+        } else if self.state != other.state {
+            bits |= DIFF_PAYLOAD;
+        }
+        bits
+    }
+
+    /// Downgrades the command to plain on or off, for older handlers that know nothing else.
+    /// States that light the led, like a blink or a pattern, become On at full brightness.
+    /// A brightness of 0, a black color, an all-zero ramp and a fade out become Off.
+    /// Returns None for failed commands, toggles, which depend on the current state, and
+    /// commands other than `CommandKind::Set`.
+    /// # Example
+    /// in:                         out:
+    /// Command(Led1, On(128))      Some(Command(Led1, On))
+    /// Command(Led1, On(0))        Some(Command(Led1, Off))
+    /// Command(Led2, Blink(500))   Some(Command(Led2, On))
+    /// Command(Status)             None
+    pub fn to_basic(&self) -> Option<Command> {
+        if !self.success || self.kind != CommandKind::Set {
+            return None;
+        }
+        let on = match self.state {
+            LedState::On { brightness } => brightness != 0,
+            LedState::Off => false,
+            LedState::Toggle => return None,
+            #[cfg(feature = "extended-states")]
+            LedState::FadeOut { .. } => false,
+            #[cfg(feature = "extended-states")]
+            LedState::Blink { .. } | LedState::Pattern { .. } => true,
+            #[cfg(feature = "extended-states")]
+            LedState::Rgb { r, g, b } => [r, g, b] != [0; 3],
+            #[cfg(feature = "extended-states")]
+            LedState::Ramp { values, .. } => values != [0; MAX_ARGS],
+        };
+        let state = if on { LedState::ON } else { LedState::Off };
+        Some(Command { state, ..*self })
+    }
+
+    /// Packs the command into a 16-bit value for dispatch tables.
+    /// Set commands give `(led << 8) | state`, with the led and state as their C enum values.
+    /// States carrying a value all share the reserved state code `OPCODE_PAYLOAD`, the value
+    /// is not part of the opcode. Other command kinds give `OPCODE_KIND | kind`, without the
+    /// bits of a mask.
+    /// # Example
+    /// Command(Led2, Off)          0x0101
+    /// Command(Led3, Blink(500))   0x02FF
+    /// Command(Status)             0xFF01
+    pub fn opcode(&self) -> u16 {
+        if self.kind != CommandKind::Set {
+            return OPCODE_KIND | self.kind.code();
+        }
+        let state = match self.state {
+            LedState::On {
+                brightness: BRIGHTNESS_FULL,
+            } => 0,
+            LedState::Off => 1,
+            LedState::Toggle => 2,
+            LedState::On { .. } => OPCODE_PAYLOAD,
+            #[cfg(feature = "extended-states")]
+            LedState::Blink { .. }
+            | LedState::FadeOut { .. }
+            | LedState::Rgb { .. }
+            | LedState::Ramp { .. }
+            | LedState::Pattern { .. } => OPCODE_PAYLOAD,
+        };
+        ((self.led as u16) << 8) | u16::from(state)
+    }
+
+    /// Writes the canonical wire form of the command (e.g. "esp led1 on") into the buffer.
+    /// Returns the number of written bytes, or None if the command was not parsed
+    /// successfully or the buffer is too small. See `canonical_len` to size the buffer.
+    pub fn to_bytes(&self, out: &mut [u8]) -> Option<usize> {
+        let mut writer = ByteWriter::new(out);
+        self.write_to(&mut writer)?;
+        Some(writer.length)
+    }
+
+    /// Same as `to_bytes`, followed by the "*XX" checksum of the written bytes, for links
+    /// that check the commands with `parse_checksummed`. See `checksum`.
+    /// # Example
+    /// in:                     out:
+    /// Command(Led1, On)       Some(14), "esp led1 on*3B"
+    pub fn checksum_bytes(&self, out: &mut [u8]) -> Option<usize> {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        let length = self.to_bytes(out)?;
+        let sum = checksum(&out[..length]);
+        let suffix = out.get_mut(length..(length + 3))?;
+        suffix.copy_from_slice(&[
+            b'*',
+            HEX[usize::from(sum >> 4)],
+            HEX[usize::from(sum & 0xF)],
+        ]);
+        Some(length + 3)
+    }
+
+    /// Returns the number of bytes `to_bytes` writes for this command, 0 if the command was
+    /// not parsed successfully.
+    pub fn canonical_len(&self) -> usize {
+        let mut writer = ByteWriter::counter();
+        match self.write_to(&mut writer) {
+            Some(()) => writer.length,
+            None => 0,
+        }
+    }
+
+    /// Writes the canonical wire form of the command, see `to_bytes`.
+    fn write_to(&self, writer: &mut ByteWriter) -> Option<()> {
+        const ESP: &[u8] = b"esp";
+        const TIMES: &[u8] = b"x";
+
+        if !self.success {
+            return None;
+        }
+        writer.push(ESP)?;
+        if self.kind == CommandKind::Help {
+            return Some(());
+        }
+        if self.addr != 0 {
+            writer.push(b"@")?;
+            writer.number(self.addr.into())?;
+        }
+        if self.repeat != 1 {
+            writer.token(&[])?;
+            writer.number(self.repeat.into())?;
+            writer.push(TIMES)?;
+        }
+        match self.priority {
+            PRIORITY_LOW => writer.token(b"!lo")?,
+            PRIORITY_HIGH => writer.token(b"!hi")?,
+            _ => (),
+        }
+        if self.seq != 0 {
+            writer.token(b"#")?;
+            writer.number(self.seq.into())?;
+        }
+        if self.at_tick != 0 {
+            writer.token(b"@")?;
+            writer.number(self.at_tick)?;
+        }
+        if self.ack_requested {
+            writer.token(b"ack")?;
+        }
+        if self.kind != CommandKind::Set {
+            writer.token(self.kind.keyword()?)?;
+            if let CommandKind::Mask { bits: value } | CommandKind::Fault { code: value } =
+                self.kind
+            {
+                writer.token(&[])?;
+                writer.number(value.into())?;
+            }
+            if let CommandKind::Cycle { step_ms } = self.kind {
+                writer.token(&[])?;
+                writer.number(step_ms.into())?;
+            }
+            if let Some((name, leds)) = self.kind.group() {
+                writer.token(name)?;
+                for led in leds {
+                    writer.token(led.token())?;
+                }
+            }
+            return Some(());
+        }
+        writer.token(self.led.token())?;
+        if !self.latched {
+            writer.token(b"hold")?;
+        }
+        match self.state {
+            LedState::On { brightness } => {
+                writer.token(b"on")?;
+                if brightness != BRIGHTNESS_FULL {
+                    writer.token(&[])?;
+                    writer.number(brightness.into())?;
+                }
+            }
+            LedState::Off => writer.token(b"off")?,
+            LedState::Toggle => writer.token(b"toggle")?,
+            #[cfg(feature = "extended-states")]
+            LedState::Blink { period_ms } => {
+                writer.token(b"blink")?;
+                writer.token(&[])?;
+                writer.number(period_ms.into())?;
+            }
+            #[cfg(feature = "extended-states")]
+            LedState::FadeOut { duration_ms } => {
+                writer.token(b"fadeout")?;
+                writer.token(&[])?;
+                writer.number(duration_ms.into())?;
+            }
+            #[cfg(feature = "extended-states")]
+            LedState::Rgb { r, g, b } => {
+                writer.token(b"rgb")?;
+                for channel in [r, g, b] {
+                    writer.token(&[])?;
+                    writer.number(channel.into())?;
+                }
+            }
+            #[cfg(feature = "extended-states")]
+            LedState::Ramp { values, count } => {
+                writer.token(b"ramp")?;
+                for value in values.iter().take(count.into()) {
+                    writer.token(&[])?;
+                    writer.number((*value).into())?;
+                }
+            }
+            #[cfg(feature = "extended-states")]
+            LedState::Pattern { bits, len } => {
+                writer.token(b"pattern")?;
+                writer.token(&[])?;
+                for bit in (0..len).rev() {
+                    writer.push(if bits >> bit & 1 == 1 { b"1" } else { b"0" })?;
+                }
+            }
+        }
+        if self.fade_in_ms != 0 {
+            writer.token(b"fadein")?;
+            writer.token(&[])?;
+            writer.number(self.fade_in_ms.into())?;
+        }
+        if self.auto_off_ms != 0 {
+            writer.token(b"for")?;
+            writer.token(&[])?;
+            writer.number(self.auto_off_ms.into())?;
+        }
+        Some(())
+    }
+}
+
+/// The failed command, see `Command::safe_default`. Usable in const and static position,
+/// e.g. `static LAST_COMMAND: Command = DEFAULT_COMMAND`.
+pub const DEFAULT_COMMAND: Command = Command::safe_default();
+
+/// The failed command, see `Command::safe_default`.
+impl Default for Command {
+    fn default() -> Self {
+        Command::safe_default()
+    }
+}
+
+/// Longest command, in bytes, that needs to be held in a buffer by the parser.
+pub const MAX_FRAME_LEN: usize = 64;
+
+/// State code of `Command::opcode` for the states carrying a value.
+pub const OPCODE_PAYLOAD: u8 = 0xFF;
+/// High byte of `Command::opcode` for commands that are not `CommandKind::Set`.
+pub const OPCODE_KIND: u16 = 0xFF00;
+
+/// Bit of `Command::diff` for a changed led.
+pub const DIFF_LED: u8 = 1 << 0;
+/// Bit of `Command::diff` for a changed state, e.g. On to Blink.
+pub const DIFF_STATE: u8 = 1 << 1;
+/// Bit of `Command::diff` for the same state with another value, e.g. Blink 500 to Blink 250.
+pub const DIFF_PAYLOAD: u8 = 1 << 2;
+
+/// Reason why a command could not be parsed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub enum ParseError {
+    /// The command does not start with the keyword "esp ".
+    MissingPrefix,
+    /// No led was found after the keyword.
+    UnknownLed,
+    /// Nothing follows the led.
+    MissingState,
+    /// The led is not followed by a known state.
+    UnknownState,
+    /// The input contains bytes that are not ASCII, see `BoardConfig::ascii_only`.
+    NonAscii,
+    /// The repeat count is 0, too large or not followed by a space.
+    InvalidRepeat,
+    /// The priority modifier is neither "!lo" nor "!hi".
+    InvalidPriority,
+    /// The sequence number is missing, too large or not followed by a space.
+    InvalidSequence,
+    /// The timestamp is missing, too large or not followed by a space.
+    InvalidTimestamp,
+    /// Something else than blanks follows the command.
+    TrailingInput,
+    /// The input is longer than the board's frame limit, see `BoardConfig::max_frame`.
+    FrameTooLong,
+    /// The keyword is neither a known command nor a led, e.g. "esp wibble".
+    UnknownCommand,
+    /// A second state follows the state, e.g. "esp led1 on off". It is ambiguous which one
+    /// is meant.
+    MultipleStates,
+    /// A led is given twice in a list, e.g. "esp led1,led1 on".
+    DuplicateTarget,
+    /// The mask is missing, or sets the bit of a led the board does not have.
+    InvalidMask,
+    /// The "*XX" checksum is missing, malformed or does not match, see `parse_checksummed`.
+    InvalidChecksum,
+    /// A group definition has no valid name, no leds or more than `MAX_LED` leds.
+    InvalidGroup,
+    /// The code of a fault is missing or not a number from 0 to 255, e.g. "esp fault".
+    InvalidFault,
+    /// The address after the prefix is not a number from 0 to 255, e.g. "esp@x led1 on".
+    InvalidAddress,
+    /// The duration after "for" is missing, invalid or 0, e.g. "esp led1 on for".
+    InvalidAutoOff,
+    /// A led or state is split by a stray space, e.g. "esp le d1 on" or "esp led1 o n".
+    /// Reported instead of `UnknownLed`, `UnknownCommand` or `UnknownState`, see `is_split`.
+    SplitToken,
+    /// Nothing follows the prefix but blanks, e.g. "esp ". A bare "esp" without the space
+    /// is a help request, see `CommandKind::Help`.
+    EmptyCommand,
+    /// The command is addressed to another node, see `parse_with_addr`.
+    NotAddressed,
+    /// The input contains a control byte other than a tab, `\r` or `\n`, e.g. the escape of
+    /// a terminal sequence or a backspace (0x08 or DEL).
+    ControlChar,
+    /// The step of a cycle is missing, invalid or 0, e.g. "esp cycle".
+    InvalidCycle,
+    /// The duration after "fadein" is missing, invalid or 0, e.g. "esp led1 on fadein".
+    InvalidFadeIn,
+}
+
+impl ParseError {
+    /// Short human-readable description of the error, e.g. "unknown state".
+    pub fn description(&self) -> &'static str {
+        match self {
+            ParseError::MissingPrefix => "missing keyword \"esp \"",
+            ParseError::UnknownLed => "unknown led",
+            ParseError::MissingState => "missing state",
+            ParseError::UnknownState => "unknown state",
+            ParseError::NonAscii => "non-ASCII byte",
+            ParseError::InvalidRepeat => "invalid repeat count",
+            ParseError::InvalidPriority => "invalid priority",
+            ParseError::InvalidSequence => "invalid sequence number",
+            ParseError::InvalidTimestamp => "invalid timestamp",
+            ParseError::TrailingInput => "trailing input",
+            ParseError::FrameTooLong => "frame too long",
+            ParseError::UnknownCommand => "unknown command",
+            ParseError::MultipleStates => "multiple states",
+            ParseError::DuplicateTarget => "duplicate led",
+            ParseError::InvalidMask => "invalid mask",
+            ParseError::InvalidChecksum => "invalid checksum",
+            ParseError::InvalidGroup => "invalid group",
+            ParseError::InvalidFault => "invalid fault",
+            ParseError::InvalidAddress => "invalid address",
+            ParseError::InvalidAutoOff => "invalid auto-off",
+            ParseError::SplitToken => "stray space in a token",
+            ParseError::EmptyCommand => "empty command",
+            ParseError::NotAddressed => "addressed to another node",
+            ParseError::ControlChar => "control character",
+            ParseError::InvalidCycle => "invalid cycle step",
+            ParseError::InvalidFadeIn => "invalid fade-in",
+        }
+    }
+
+    /// Writes the description of the error, e.g. into a `heapless::String` or a buffer.
+    pub fn write_to(&self, f: &mut impl Write) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_to(f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// A `ParseError` with the offset of the byte where it happened, see `parse_positioned`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ParseErrorAt {
+    pub error: ParseError,
+    /// Offset in the input, the input length if the input ended too early.
+    pub offset: usize,
+}
+
+impl ParseErrorAt {
+    /// Writes the error with its position, e.g. "unknown state at byte 9".
+    pub fn write_to(&self, f: &mut impl Write) -> fmt::Result {
+        self.error.write_to(f)?;
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+impl fmt::Display for ParseErrorAt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_to(f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseErrorAt {}
+
+impl From<ParseErrorAt> for ParseError {
+    fn from(error: ParseErrorAt) -> Self {
+        error.error
+    }
+}
+
+/// Limits of a board variant, applied by `parse_with_config`.
+/// `BoardConfig::DEFAULT` describes this board and is used by `parse`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BoardConfig {
+    /// Number of leds fitted, from 1 to `MAX_LED`. Commands on other leds are rejected.
+    pub leds: u8,
+    /// Longest accepted input, in bytes.
+    pub max_frame: usize,
+    /// Rejects any input containing a byte that is not ASCII before matching the tokens.
+    /// Such a byte means garbled data, unless the link appends binary bytes like checksums.
+    pub ascii_only: bool,
+    /// Keyword every command starts with, including its space. Empty on a point-to-point
+    /// link where the framing already guarantees the target, see `parse_no_prefix`.
+    pub prefix: &'static [u8],
+}
+
+impl BoardConfig {
+    /// This board: `MAX_LED` leds and ASCII frames of up to `MAX_FRAME_LEN` bytes, starting
+    /// with "esp ".
+    pub const DEFAULT: BoardConfig = BoardConfig {
+        leds: MAX_LED,
+        max_frame: MAX_FRAME_LEN,
+        ascii_only: true,
+        prefix: b"esp ",
+    };
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Parses a command from a byte slice. Unlike the FFI, the reason of a failure is reported.
+/// Apart from blanks, nothing may follow the command, see `parse_lenient` otherwise.
+/// The keywords "esp", "led", "on" and "off" ignore the case, see `strip_keyword`.
+/// # Example
+/// in:                     out:
+/// input = "esp led1 on"   Ok(Command(Led1, On))
+/// input = "ESP LED1 ON"   Ok(Command(Led1, On))
+/// input = "esp"           Ok(Command(Help))
+/// input = "esp led1"      Err(ParseError::MissingState)
+/// input = "esp led1 on x" Err(ParseError::TrailingInput)
+pub fn parse(input: &[u8]) -> Result<Command, ParseError> {
+    parse_with_config(input, &BoardConfig::DEFAULT)
+}
+
+/// Same as `parse`, but with the limits of the given board variant.
+/// # Example
+/// config = BoardConfig { leds: 2, .. }
+/// in:                     out:
+/// input = "esp led2 on"   Ok(Command(Led2, On))
+/// input = "esp led3 on"   Err(ParseError::UnknownLed)
+pub fn parse_with_config(input: &[u8], config: &BoardConfig) -> Result<Command, ParseError> {
+    parse_complete(input, config, None).map_err(|(error, _)| error)
+}
+
+/// Same as `parse`, for a node with the address `local` on a multi-drop bus. Commands
+/// addressed to another node are rejected, commands without address are for every node.
+/// # Example
+/// local = 2
+/// in:                     out:
+/// input = "esp@2 led1 on" Ok(Command(Led1, On))
+/// input = "esp led1 on"   Ok(Command(Led1, On))
+/// input = "esp@3 led1 on" Err(ParseError::NotAddressed)
+pub fn parse_with_addr(input: &[u8], local: u8) -> Result<Command, ParseError> {
+    let command = parse(input)?;
+    if command.addr != 0 && command.addr != local {
+        return Err(ParseError::NotAddressed);
+    }
+    Ok(command)
+}
+
+/// Generate a command that may address the led relative to the current one of a scanning
+/// UI: "next" is the led after `current`, "prev" the one before it, see `Led::next` and
+/// `Led::prev`.
+/// # Example
+/// current = Led1
+/// in:                     out:
+/// input = "esp next on"   Command(Led2, On)
+/// input = "esp prev on"   Command(Led4, On)
+pub fn parse_relative(input: &[u8], current: Led) -> Command {
+    parse_complete(input, &BoardConfig::DEFAULT, Some(current)).unwrap_or_default()
+}
+
+/// Generate a command from the first `len` bytes of a receive buffer. A `len` larger than
+/// the buffer is clamped to its size instead of panicking.
+/// # Example
+/// buf = "esp led1 on\0\0\0\0\0"
+/// in:         out:
+/// len = 11    Command(Led1, On)
+/// len = 99    Command(success: false), the whole buffer is parsed
+pub fn parse_buf<const N: usize>(buf: &[u8; N], len: usize) -> Command {
+    Command::from_slice(&buf[..len.min(N)])
+}
+
+/// Generate a command without the keyword "esp ", e.g. "led1 on", for a trusted
+/// point-to-point link. A command with the keyword fails, like any other malformed command.
+/// # Example
+/// in:                     out:
+/// input = "led1 on"       Command(Led1, On)
+/// input = "esp led1 on"   Command(success: false)
+pub fn parse_no_prefix(input: &[u8]) -> Command {
+    let config = BoardConfig {
+        prefix: b"",
+        ..BoardConfig::DEFAULT
+    };
+    parse_with_config(input, &config).unwrap_or_default()
+}
+
+/// Generate a command whose tokens are separated by `sep` instead of a space, e.g.
+/// "esp:led1:on" for a colon-delimited transport. `parse` is the same with a space.
+/// # To know:
+/// Each separator is turned into a space in a stack buffer of `MAX_FRAME_LEN` bytes, then
+/// the command is parsed normally. A space in the input, or a longer command, fails.
+/// # Example
+/// in:                             out:
+/// input = "esp:led1:on", sep ':'  Command(Led1, On)
+/// input = "esp:led1 on", sep ':'  Command(success: false)
+pub fn parse_with_sep(input: &[u8], sep: u8) -> Command {
+    const SPACE: u8 = b' ';
+
+    if input.len() > MAX_FRAME_LEN || (sep != SPACE && input.contains(&SPACE)) {
+        return Command::safe_default();
+    }
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    for (byte, input) in frame.iter_mut().zip(input) {
+        *byte = if *input == sep { SPACE } else { *input };
+    }
+    Command::from_slice(&frame[..input.len()])
+}
+
+/// Nom parser of a whole command, to embed the grammar in a larger nom parser. The input
+/// after the command is returned, it is not checked. Like every token, the command must
+/// be followed by a space, a control byte like ETX or the end of the input.
+/// # To know:
+/// Unlike `parse`, no frame length limit and no ASCII check are applied, the input may
+/// hold more than the command. The modifiers are validated but not part of the result.
+/// The signature is stable.
+/// # Example
+/// in:                         out:
+/// input = "esp led1 on *42"   Ok(ParsedCommand::Set(Led1, On), " *42")
+/// input = "esp led9 on"       Err("esp led9 on")
+pub fn command_parser(input: &[u8]) -> IResult<&[u8], ParsedCommand> {
+    let config = BoardConfig {
+        max_frame: usize::MAX,
+        ascii_only: false,
+        ..BoardConfig::DEFAULT
+    };
+    let Ok((rest, command)) = parse_command(input, &config, None) else {
+        return fail(input);
+    };
+    match ParsedCommand::from_ffi(&command) {
+        Some(parsed) => Ok((rest, parsed)),
+        None => fail(input),
+    }
+}
+
+/// Same as `parse`, but a failure also reports where it happened in the input, for logs.
+/// # Example
+/// in:                     out:
+/// input = "esp led1 wfea" Err(ParseErrorAt { error: ParseError::UnknownState, offset: 9 })
+pub fn parse_positioned(input: &[u8]) -> Result<Command, ParseErrorAt> {
+    parse_complete(input, &BoardConfig::DEFAULT, None).map_err(|(error, rest)| ParseErrorAt {
+        error,
+        offset: input.len() - rest.len(),
+    })
+}
+
+/// XOR of all the bytes of a command, the checksum of `parse_checksummed`.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// Same as `parse`, for a command followed by its checksum, "*" and two hex digits of either
+/// case. The checksum is the XOR of all the bytes before the "*", like in NMEA sentences.
+/// `Command::checksum_bytes` writes such commands.
+/// # Example
+/// in:                         out:
+/// input = "esp led1 on*3B"    Ok(Command(Led1, On))
+/// input = "esp led1 on*00"    Err(ParseError::InvalidChecksum)
+/// input = "esp led1 on"       Err(ParseError::InvalidChecksum)
+pub fn parse_checksummed(input: &[u8]) -> Result<Command, ParseError> {
+    const STAR: u8 = b'*';
+
+    let input = trim(input);
+    let star = input
+        .iter()
+        .rposition(|byte| *byte == STAR)
+        .ok_or(ParseError::InvalidChecksum)?;
+    let (command, digits) = (&input[..star], &input[(star + 1)..]);
+    if digits.len() != 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return Err(ParseError::InvalidChecksum);
+    }
+    let expected = core::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    if expected != Some(checksum(command)) {
+        return Err(ParseError::InvalidChecksum);
+    }
+    parse(command)
+}
+
+/// A parse error with the input from the failing position on.
+type Failure<I> = (ParseError, I);
+
+/// Parses a command that nothing but blanks may follow. A failure comes with the input
+/// from the failing position on.
+fn parse_complete<I: Input>(
+    input: I,
+    config: &BoardConfig,
+    current: Option<Led>,
+) -> Result<Command, Failure<I>> {
+    check_control(input)?;
+    let (input, command) = parse_command(input, config, current)?;
+    if !trim(input).is_empty() {
+        let blanks = input
+            .iter_elements()
+            .take_while(|byte| matches!(byte, b' ' | b'\t' | b'\r'))
+            .count();
+        return Err((ParseError::TrailingInput, input.slice(blanks..)));
+    }
+    Ok(command)
+}
+
+/// Same as `parse`, but unexpected input after the command is accepted. It is reported by
+/// the returned flag instead, so the caller decides whether the command is honored.
+/// The trailing input may be binary, `BoardConfig::ascii_only` is not applied.
+/// # Example
+/// in:                         out:
+/// input = "esp led1 on"       Ok(Command(Led1, On), false)
+/// input = "esp led1 on junk"  Ok(Command(Led1, On), true)
+pub fn parse_lenient(input: &[u8]) -> Result<(Command, bool), ParseError> {
+    let config = BoardConfig {
+        ascii_only: false,
+        ..BoardConfig::DEFAULT
+    };
+    let (input, command) = parse_command(input, &config, None).map_err(|(error, _)| error)?;
+    Ok((command, !trim(input).is_empty()))
+}
+
+/// Fails with `ParseError::ControlChar` if the frame holds a control byte other than a tab,
+/// `\r` or `\n`. Leaked terminal sequences must not match a token partially, e.g.
+/// "esp led1 o\x08on". Only applied to complete frames, the input after an embedded
+/// command, see `command_parser` and `parse_lenient`, may be binary.
+fn check_control<I: Input>(input: I) -> Result<(), Failure<I>> {
+    let is_control = |byte: u8| byte.is_ascii_control() && !matches!(byte, b'\t' | b'\r' | b'\n');
+    match input.position(is_control) {
+        Some(pos) => Err((ParseError::ControlChar, input.slice(pos..))),
+        None => Ok(()),
+    }
+}
+
+/// Checks a complete frame on the default board like `parse` does, for the parsers that
+/// expand a command into several: frame length, ASCII only and no control bytes.
+fn check_frame(input: &[u8]) -> Result<(), ParseError> {
+    if input.len() > BoardConfig::DEFAULT.max_frame {
+        return Err(ParseError::FrameTooLong);
+    }
+    if !input.is_ascii() {
+        return Err(ParseError::NonAscii);
+    }
+    check_control(input).map_err(|(error, _)| error)
+}
+
+/// Parses a command within the limits of the board, returning the input that follows it.
+/// A failure comes with the input from the failing position on.
+/// With a `current` led, the led may also be given as "next" or "prev".
+fn parse_command<I: Input>(
+    input: I,
+    config: &BoardConfig,
+    current: Option<Led>,
+) -> Result<(I, Command), Failure<I>> {
+    if input.len() > config.max_frame {
+        return Err((ParseError::FrameTooLong, input.slice(config.max_frame..)));
+    }
+    if config.ascii_only {
+        if let Some(pos) = input.position(|byte| !byte.is_ascii()) {
+            return Err((ParseError::NonAscii, input.slice(pos..)));
+        }
+    }
+    let mut command = CommandBuilder::new().build();
+    if is_help(input, config.prefix) {
+        command.kind = CommandKind::Help;
+        return Ok((input.slice(input.len()..), command));
+    }
+    // Check if the command starts with the prefix, the keyword "esp " by default.
+    let input =
+        command_prefix(input, config.prefix, &mut command).map_err(|error| (error, input))?;
+    if trim(input).is_empty() {
+        return Err((ParseError::EmptyCommand, input));
+    }
+    let input = modifiers(input, &mut command)?;
+    let mask_input = input;
+    if let Some((input, bits)) = mask_command(input).map_err(|error| (error, input))? {
+        // A board with 32 leds or more takes every mask, the shift would overflow there.
+        if u32::from(bits)
+            .checked_shr(u32::from(config.leds))
+            .unwrap_or(0)
+            != 0
+        {
+            return Err((ParseError::InvalidMask, mask_input));
+        }
+        command.kind = CommandKind::Mask { bits };
+        return Ok((input, command));
+    }
+    if let Some((input, code)) = fault_command(input).map_err(|error| (error, input))? {
+        command.kind = CommandKind::Fault { code };
+        return Ok((input, command));
+    }
+    if let Some((input, step_ms)) = cycle_command(input).map_err(|error| (error, input))? {
+        command.kind = CommandKind::Cycle { step_ms };
+        return Ok((input, command));
+    }
+    let group_input = input;
+    if let Some((input, kind)) = define_group(input).map_err(|error| (error, input))? {
+        let (_, leds) = kind.group().unwrap_or_default();
+        if leds.iter().any(|led| led.index().get() > config.leds) {
+            return Err((ParseError::UnknownLed, group_input));
+        }
+        command.kind = kind;
+        return Ok((input, command));
+    }
+    if let Some((input, kind)) = CommandKind::from_keyword(input) {
+        command.kind = kind;
+        return Ok((input, command));
+    }
+    // Extract the LED and state.
+    let result = set_target(input, config, current, &mut command);
+    #[cfg(not(feature = "strict"))]
+    let result = match result {
+        Err(
+            failure @ (
+                ParseError::UnknownLed | ParseError::UnknownState | ParseError::SplitToken,
+                _,
+            ),
+        ) => loose_target(input, config, &mut command).ok_or(failure),
+        // A led run into other letters, like "xled3", is an unknown command for the strict
+        // matching.
+        Err(failure @ (ParseError::UnknownCommand, _))
+            if input
+                .iter_elements()
+                .take_while(|byte| *byte != b' ')
+                .any(|byte| byte.is_ascii_digit()) =>
+        {
+            loose_target(input, config, &mut command).ok_or(failure)
+        }
+        result => result,
+    };
+    Ok((result?, command))
+}
+
+/// Parses the led and the state of a set command into `command`, returning the input that
+/// follows them. See `parse_command`.
+fn set_target<I: Input>(
+    input: I,
+    config: &BoardConfig,
+    current: Option<Led>,
+    command: &mut Command,
+) -> Result<I, Failure<I>> {
+    const SPACE: &[u8] = b" ";
+
+    let led_input = input;
+    let (input, led) = match current.and_then(|current| relative_led(input, current)) {
+        Some(found) => found,
+        None => Led::from_input(input).map_err(|_| match is_split(input, Led::from_slice) {
+            true => (ParseError::SplitToken, input),
+            false => (unknown_token(input), input),
+        })?,
+    };
+    if led.index().get() > config.leds {
+        return Err((ParseError::UnknownLed, led_input));
+    }
+    if input.is_empty() {
+        return Err((ParseError::MissingState, input));
+    }
+    let (input, _) =
+        tag::<_, _, Error<_>>(SPACE)(input).map_err(|_| (ParseError::UnknownState, input))?;
+    let (input, latched) = hold_modifier(input);
+    let (input, state) =
+        LedState::from_input(input).map_err(|_| match is_split(input, LedState::from_slice) {
+            true => (ParseError::SplitToken, input),
+            false => (ParseError::UnknownState, input),
+        })?;
+    let (input, fade_in_ms) = fade_in(input, state).map_err(|error| (error, input))?;
+    let (input, auto_off_ms) = auto_off(input, state).map_err(|error| (error, input))?;
+    single_state(input)?;
+    command.led = led;
+    command.state = state;
+    command.latched = latched;
+    command.auto_off_ms = auto_off_ms;
+    command.fade_in_ms = fade_in_ms;
+    Ok(input)
+}
+
+/// Fails with `ParseError::MultipleStates` if a second state follows the state of a set
+/// command, e.g. "on off". Shared by `set_target` and `parse_list`.
+fn single_state<I: Input>(input: I) -> Result<(), Failure<I>> {
+    const SPACE: &[u8] = b" ";
+
+    match input.strip_prefix(SPACE) {
+        Some(next) if LedState::from_input(next).is_ok() => Err((ParseError::MultipleStates, next)),
+        _ => Ok(()),
+    }
+}
+
+/// Searches a slice in a slice. If the needle is found in the haystack, the position of the first
+/// matching byte is returned. If no needle is found, None is returned.
+#[cfg(not(feature = "strict"))]
+fn find_subsequence<I: Input>(haystack: I, needle: &[u8]) -> Option<usize> {
+    (0..haystack.len()).find(|at| haystack.slice(*at..).starts_with(needle))
+}
+
+/// The legacy matching of the led and the state, for firmwares that still rely on it. Only
+/// used without the default feature `strict`, once the strict matching did not recognize
+/// the led or the state.
+/// # To know:
+/// The first led token found anywhere in the input is the led, "on" or "off" found anywhere
+/// after it the state. This means it detects things like "led11 on" or "led2 offasdf", the
+/// whole input is consumed. The other states and the modifiers of a led are not matched.
+/// # Example
+/// in:                 out:
+/// input = "led11 on"  Some(()), Command(Led1, On)
+/// input = "led2 oon"  Some(()), Command(Led2, On)
+/// input = "led9 on"   None
+#[cfg(not(feature = "strict"))]
+fn loose_target<I: Input>(input: I, config: &BoardConfig, command: &mut Command) -> Option<I> {
+    const ON: &[u8] = b"on";
+    const OFF: &[u8] = b"off";
+
+    let (end, led) = LED_TOKENS
+        .iter()
+        .find_map(|(token, led)| Some((find_subsequence(input, token)? + token.len(), *led)))?;
+    if led.index().get() > config.leds {
+        return None;
+    }
+    let rest = input.slice(end..);
+    let state = if find_subsequence(rest, ON).is_some() {
+        LedState::ON
+    } else if find_subsequence(rest, OFF).is_some() {
+        LedState::Off
+    } else {
+        return None;
+    };
+    command.led = led;
+    command.state = state;
+    Some(input.slice(input.len()..))
+}
+
+/// Parses the fade-in suffix after an On state, " fadein" followed by a duration like the
+/// one of a blink, see `duration_ms`. Comes before an auto-off, "on fadein 300 for 2s".
+/// Returns 0 without the suffix. Other states take no suffix, it is left in the input, a led
+/// that turns off has nothing to fade in.
+/// # Example
+/// in:                             out:
+/// input = " fadein 300", On       Ok((), 300)
+/// input = " fadein 1s for 5s", On Ok(" for 5s", 1000)
+/// input = "", On                  Ok((), 0)
+/// input = " fadein 0", On         Err(ParseError::InvalidFadeIn)
+fn fade_in<I: Input>(input: I, state: LedState) -> Result<(I, u16), ParseError> {
+    const FADE_IN: &[u8] = b" fadein";
+    const SPACE: &[u8] = b" ";
+
+    let suffix = input
+        .strip_prefix(FADE_IN)
+        .filter(|rest| is_token_end(*rest));
+    let Some(rest) = suffix.filter(|_| matches!(state, LedState::On { .. })) else {
+        return Ok((input, 0));
+    };
+    let rest = rest.strip_prefix(SPACE).ok_or(ParseError::InvalidFadeIn)?;
+    match duration_ms(rest) {
+        Ok((rest, duration)) if duration != 0 => Ok((rest, duration)),
+        _ => Err(ParseError::InvalidFadeIn),
+    }
+}
+
+/// Parses the auto-off suffix after an On state, " for" followed by a duration like the
+/// one of a blink, see `duration_ms`. Returns 0 without the suffix. Other states take no
+/// suffix, it is left in the input.
+/// # Example
+/// in:                         out:
+/// input = " for 500", On      Ok((), 500)
+/// input = " for 2s x", On     Ok(" x", 2000)
+/// input = "", On              Ok((), 0)
+/// input = " for", On          Err(ParseError::InvalidAutoOff)
+fn auto_off<I: Input>(input: I, state: LedState) -> Result<(I, u16), ParseError> {
+    const FOR: &[u8] = b" for";
+    const SPACE: &[u8] = b" ";
+
+    let suffix = input.strip_prefix(FOR).filter(|rest| is_token_end(*rest));
+    let Some(rest) = suffix.filter(|_| matches!(state, LedState::On { .. })) else {
+        return Ok((input, 0));
+    };
+    let rest = rest.strip_prefix(SPACE).ok_or(ParseError::InvalidAutoOff)?;
+    match duration_ms(rest) {
+        Ok((rest, duration)) if duration != 0 => Ok((rest, duration)),
+        _ => Err(ParseError::InvalidAutoOff),
+    }
+}
+
+/// Checks if the input is the bare keyword of the prefix, "esp", which asks for help.
+/// Unlike in "esp ", no space follows the keyword.
+/// # Example
+/// prefix = "esp "
+/// in:                 out:
+/// input = "esp"       true
+/// input = "esp\r"     true
+/// input = "esp "      false
+fn is_help<I: Input>(input: I, prefix: &[u8]) -> bool {
+    const SPACE: &[u8] = b" ";
+
+    let Some(keyword) = prefix.strip_suffix(SPACE) else {
+        return false;
+    };
+    strip_keyword(input, keyword)
+        .is_some_and(|rest| !rest.starts_with(SPACE) && trim(rest).is_empty())
+}
+
+/// Parses the prefix followed by a node address, "esp@2 ". Returns None if the input has no
+/// address, or the prefix does not end with a space.
+/// # Example
+/// prefix = "esp "
+/// in:                     out:
+/// input = "esp@2 led1 on" Ok(Some(("led1 on", 2)))
+/// input = "esp led1 on"   Ok(None)
+/// input = "esp@x led1 on" Err(ParseError::InvalidAddress)
+fn address_prefix<I: Input>(input: I, prefix: &[u8]) -> Result<Option<(I, u8)>, ParseError> {
+    const AT: &[u8] = b"@";
+    const SPACE: &[u8] = b" ";
+
+    let Some(keyword) = prefix.strip_suffix(SPACE) else {
+        return Ok(None);
+    };
+    let Some(input) = strip_keyword(input, keyword).and_then(|input| input.strip_prefix(AT)) else {
+        return Ok(None);
+    };
+    let (input, addr) =
+        character::u8::<_, Error<_>>(input).map_err(|_| ParseError::InvalidAddress)?;
+    let input = input
+        .strip_prefix(SPACE)
+        .ok_or(ParseError::InvalidAddress)?;
+    Ok(Some((input, addr)))
+}
+
+/// Strips the prefix, with or without a node address, "esp " or "esp@2 ". The address goes
+/// into `command`, see `Command::addr`. Shared by every parser of the prefix.
+/// # Example
+/// prefix = "esp "
+/// in:                     out:
+/// input = "esp@2 led1 on" Ok("led1 on"), addr 2
+/// input = "esp led1 on"   Ok("led1 on"), addr 0
+/// input = "led1 on"       Err(ParseError::MissingPrefix)
+fn command_prefix<I: Input>(
+    input: I,
+    prefix: &[u8],
+    command: &mut Command,
+) -> Result<I, ParseError> {
+    match address_prefix(input, prefix)? {
+        Some((input, addr)) => {
+            command.addr = addr;
+            Ok(input)
+        }
+        None => strip_keyword(input, prefix).ok_or(ParseError::MissingPrefix),
+    }
+}
+
+/// Strips the "hold" modifier before a state. Returns whether the state is latched, i.e.
+/// the modifier is absent.
+/// # Example
+/// in:                 out:
+/// input = "hold on"   ("on", false)
+/// input = "on"        ("on", true)
+fn hold_modifier<I: Input>(input: I) -> (I, bool) {
+    const HOLD: &[u8] = b"hold ";
+
+    match input.strip_prefix(HOLD) {
+        Some(input) => (input, false),
+        None => (input, true),
+    }
+}
+
+/// Parses the mask command "mask N", e.g. "esp mask 5". The bits beyond the leds of the
+/// board are checked by the caller.
+/// # Example
+/// in:                 out:
+/// input = "mask 5"    Ok(Some((), 5))
+/// input = "led1 on"   Ok(None)
+/// input = "mask x"    Err(ParseError::InvalidMask)
+fn mask_command<I: Input>(input: I) -> Result<Option<(I, u8)>, ParseError> {
+    const MASK: &[u8] = b"mask";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = strip_token(input, MASK) else {
+        return Ok(None);
+    };
+    let input = input.strip_prefix(SPACE).ok_or(ParseError::InvalidMask)?;
+    match character::u8::<_, Error<_>>(input) {
+        Ok((input, bits)) if is_token_end(input) => Ok(Some((input, bits))),
+        _ => Err(ParseError::InvalidMask),
+    }
+}
+
+/// Parses the fault command "fault N", e.g. "esp fault 3".
+/// # Example
+/// in:                 out:
+/// input = "fault 3"   Ok(Some((), 3))
+/// input = "led1 on"   Ok(None)
+/// input = "fault"     Err(ParseError::InvalidFault)
+fn fault_command<I: Input>(input: I) -> Result<Option<(I, u8)>, ParseError> {
+    const FAULT: &[u8] = b"fault";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = strip_token(input, FAULT) else {
+        return Ok(None);
+    };
+    let input = input.strip_prefix(SPACE).ok_or(ParseError::InvalidFault)?;
+    match character::u8::<_, Error<_>>(input) {
+        Ok((input, code)) if is_token_end(input) => Ok(Some((input, code))),
+        _ => Err(ParseError::InvalidFault),
+    }
+}
+
+/// Parses the cycle command "cycle" followed by the step duration, e.g. "esp cycle 200".
+/// The duration takes the units of `duration_ms` and cannot be 0.
+/// # Example
+/// in:                 out:
+/// input = "cycle 200" Ok(Some((), 200))
+/// input = "led1 on"   Ok(None)
+/// input = "cycle"     Err(ParseError::InvalidCycle)
+fn cycle_command<I: Input>(input: I) -> Result<Option<(I, u16)>, ParseError> {
+    const CYCLE: &[u8] = b"cycle";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = strip_token(input, CYCLE) else {
+        return Ok(None);
+    };
+    let input = input.strip_prefix(SPACE).ok_or(ParseError::InvalidCycle)?;
+    match duration_ms(input) {
+        Ok((input, step_ms)) if step_ms != 0 => Ok(Some((input, step_ms))),
+        _ => Err(ParseError::InvalidCycle),
+    }
+}
+
+/// Parses the definition of a group of leds, "def" followed by the name and the leds.
+/// Returns None if the input is no group definition.
+/// # To know:
+/// The name has 1 to `MAX_GROUP_NAME` ASCII letters or digits and cannot be a led. From 1
+/// to `MAX_LED` leds follow, each only once. The leds end at the first token that is not a led.
+/// # Example
+/// in:                             out:
+/// input = "def front led1 led2"   Ok(Some((), DefineGroup(front, [Led1, Led2])))
+/// input = "led1 on"               Ok(None)
+/// input = "def front"             Err(ParseError::InvalidGroup)
+/// input = "def front led1 led1"   Err(ParseError::DuplicateTarget)
+fn define_group<I: Input>(input: I) -> Result<Option<(I, CommandKind)>, ParseError> {
+    const DEF: &[u8] = b"def";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = strip_token(input, DEF) else {
+        return Ok(None);
+    };
+    let input = input.strip_prefix(SPACE).ok_or(ParseError::InvalidGroup)?;
+    let end = input.position(|byte| byte == b' ').unwrap_or(input.len());
+    let (token, mut input) = (input.slice(..end), input.slice(end..));
+    if token.is_empty()
+        || token.len() > MAX_GROUP_NAME
+        || !token
+            .iter_elements()
+            .all(|byte| byte.is_ascii_alphanumeric())
+        || Led::from_input(token).is_ok()
+    {
+        return Err(ParseError::InvalidGroup);
+    }
+    let mut name = [0; MAX_GROUP_NAME];
+    for (slot, byte) in name.iter_mut().zip(token.iter_elements()) {
+        *slot = byte;
+    }
+    let mut leds = [Led::Led1; MAX_LED as usize];
+    let mut count = 0;
+    while let Some(next) = input.strip_prefix(SPACE) {
+        let Ok((next, led)) = Led::from_input(next) else {
+            break;
+        };
+        if count == leds.len() {
+            return Err(ParseError::InvalidGroup);
+        }
+        if leds[..count].contains(&led) {
+            return Err(ParseError::DuplicateTarget);
+        }
+        leds[count] = led;
+        count += 1;
+        input = next;
+    }
+    if count == 0 {
+        return Err(ParseError::InvalidGroup);
+    }
+    let kind = CommandKind::DefineGroup {
+        leds,
+        name,
+        count: count as u8,
+        name_len: token.len() as u8,
+    };
+    Ok(Some((input, kind)))
+}
+
+/// Checks if the slice starts with a led relative to the current one, "next" or "prev".
+/// The input gets split up after the token.
+fn relative_led<I: Input>(input: I, current: Led) -> Option<(I, Led)> {
+    const NEXT: &[u8] = b"next";
+    const PREV: &[u8] = b"prev";
+
+    if let Some(rest) = strip_token(input, NEXT) {
+        return Some((rest, current.next()));
+    }
+    strip_token(input, PREV).map(|rest| (rest, current.prev()))
+}
+
+/// Checks if the first two tokens of the input are a whole token of the parser once the
+/// space between them is removed, i.e. the token is split by a stray space.
+/// # Example
+/// in:                                 out:
+/// input = "le d1 on", Led::from_slice     true
+/// input = "o n", LedState::from_slice     true
+/// input = "xyz on", Led::from_slice       false
+fn is_split<I: Input, T>(input: I, parser: impl Fn(&[u8]) -> IResult<&[u8], T>) -> bool {
+    let Some(space) = input.position(|byte| byte == b' ') else {
+        return false;
+    };
+    let end = input
+        .slice((space + 1)..)
+        .position(|byte| byte == b' ')
+        .map_or(input.len(), |pos| space + 1 + pos);
+    let length = end - 1;
+    if space == 0 || length > MAX_FRAME_LEN {
+        return false;
+    }
+    // Only the two tokens are joined, to tell the reason of a failure.
+    let mut joined = [0u8; MAX_FRAME_LEN];
+    let tokens = input.slice(..end).iter_elements().enumerate();
+    let bytes = tokens.filter(|(at, _)| *at != space).map(|(_, byte)| byte);
+    for (slot, byte) in joined.iter_mut().zip(bytes) {
+        *slot = byte;
+    }
+    matches!(parser(&joined[..length]), Ok((rest, _)) if rest.is_empty())
+}
+
+/// Reason why the token after the modifiers is neither a command keyword nor a led.
+/// # To know:
+/// Only a token written like a led, "led" or "l" followed by a digit, is a mistyped led.
+/// Anything else, like "list" or "lights", is reported as an unknown command.
+/// # Example
+/// in:                 out:
+/// input = "led9 on"   ParseError::UnknownLed
+/// input = "l12 on"    ParseError::UnknownLed
+/// input = "lights on" ParseError::UnknownCommand
+/// input = "wibble"    ParseError::UnknownCommand
+fn unknown_token<I: Input>(input: I) -> ParseError {
+    const LED: &[u8] = b"led";
+    const SHORT: &[u8] = b"l";
+    const SPACE: &[u8] = b" ";
+
+    // Same prefixes as `Led::from_slice`.
+    let rest = match strip_keyword(input, LED) {
+        Some(rest) => rest.strip_prefix(SPACE).unwrap_or(rest),
+        None => input
+            .strip_prefix(SHORT)
+            .unwrap_or(input.slice(input.len()..)),
+    };
+    match rest.first() {
+        Some(byte) if byte.is_ascii_digit() => ParseError::UnknownLed,
+        _ => ParseError::UnknownCommand,
+    }
+}
+
+/// Parses the optional modifiers between the keyword and the command into the command,
+/// e.g. "esp 3x !hi led1 on". They may come in any order, if one is given twice the last
+/// one is kept. Returns the input after the modifiers, or the input from the invalid
+/// modifier on.
+fn modifiers<I: Input>(mut input: I, command: &mut Command) -> Result<I, Failure<I>> {
+    const ACK: &[u8] = b"ack ";
+
+    loop {
+        if let Some((rest, repeat)) = repeat_prefix(input).map_err(|error| (error, input))? {
+            command.repeat = repeat;
+            input = rest;
+        } else if let Some((rest, priority)) =
+            priority_tag(input).map_err(|error| (error, input))?
+        {
+            command.priority = priority;
+            input = rest;
+        } else if let Some((rest, seq)) = sequence_tag(input).map_err(|error| (error, input))? {
+            command.seq = seq;
+            input = rest;
+        } else if let Some((rest, at_tick)) =
+            timestamp_tag(input).map_err(|error| (error, input))?
+        {
+            command.at_tick = at_tick;
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix(ACK) {
+            command.ack_requested = true;
+            input = rest;
+        } else {
+            return Ok(input);
+        }
+    }
+}
+
+/// Parses the repeat count modifier "Nx ", e.g. "esp 3x led1 on".
+/// Without it, the command is executed once.
+/// # To know:
+/// A count of 0 is rejected instead of meaning "never". "esp 0x led1 on" is most likely
+/// a typo or a hex number, silently dropping the command would hide that.
+/// # Example
+/// in:                 out:
+/// input = "3x led1"   Ok(Some("led1", 3))
+/// input = "led1"      Ok(None)
+/// input = "0x led1"   Err(ParseError::InvalidRepeat)
+fn repeat_prefix<I: Input>(input: I) -> Result<Option<(I, u8)>, ParseError> {
+    const TIMES: &[u8] = b"x";
+    const SPACE: &[u8] = b" ";
+
+    // Digits followed by "x" are a repeat count, which then has to be valid.
+    let Ok((input, digits)) = terminated(digit1::<_, Error<_>>, tag(TIMES))(input) else {
+        return Ok(None);
+    };
+    let (_, count) = character::u8::<_, Error<_>>(digits).map_err(|_| ParseError::InvalidRepeat)?;
+    if count == 0 {
+        return Err(ParseError::InvalidRepeat);
+    }
+    let (input, _) = tag::<_, _, Error<_>>(SPACE)(input).map_err(|_| ParseError::InvalidRepeat)?;
+    Ok(Some((input, count)))
+}
+
+/// Priority of a command without a priority modifier.
+pub const PRIORITY_NORMAL: u8 = 0;
+/// Priority of a command with the "!lo" modifier.
+pub const PRIORITY_LOW: u8 = 1;
+/// Priority of a command with the "!hi" modifier.
+pub const PRIORITY_HIGH: u8 = 2;
+
+/// Parses the priority modifier "!lo " or "!hi ", e.g. "esp !hi led1 on".
+/// Without it, the command has the priority `PRIORITY_NORMAL`.
+/// # Example
+/// in:                 out:
+/// input = "!hi led1"  Ok(Some("led1", PRIORITY_HIGH))
+/// input = "led1"      Ok(None)
+/// input = "!mid led1" Err(ParseError::InvalidPriority)
+fn priority_tag<I: Input>(input: I) -> Result<Option<(I, u8)>, ParseError> {
+    const MARK: &[u8] = b"!";
+    const LOW: &[u8] = b"!lo ";
+    const HIGH: &[u8] = b"!hi ";
+
+    if !input.starts_with(MARK) {
+        return Ok(None);
+    }
+    if let Some(rest) = input.strip_prefix(LOW) {
+        return Ok(Some((rest, PRIORITY_LOW)));
+    }
+    if let Some(rest) = input.strip_prefix(HIGH) {
+        return Ok(Some((rest, PRIORITY_HIGH)));
+    }
+    Err(ParseError::InvalidPriority)
+}
+
+/// Parses the sequence number modifier "#N ", e.g. "esp #42 led1 on".
+/// Without it, the sequence number is 0.
+/// # Example
+/// in:                     out:
+/// input = "#42 led1"      Ok(Some("led1", 42))
+/// input = "led1"          Ok(None)
+/// input = "#70000 led1"   Err(ParseError::InvalidSequence)
+fn sequence_tag<I: Input>(input: I) -> Result<Option<(I, u16)>, ParseError> {
+    const MARK: &[u8] = b"#";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = input.strip_prefix(MARK) else {
+        return Ok(None);
+    };
+    let (input, seq) =
+        character::u16::<_, Error<_>>(input).map_err(|_| ParseError::InvalidSequence)?;
+    let (input, _) =
+        tag::<_, _, Error<_>>(SPACE)(input).map_err(|_| ParseError::InvalidSequence)?;
+    Ok(Some((input, seq)))
+}
+
+/// Parses the timestamp modifier "@N ", e.g. "esp @1000 led1 on" to execute the command at
+/// tick 1000. Without it, the tick is 0 and the command is executed immediately.
+/// # Example
+/// in:                 out:
+/// input = "@1000 led1"    Ok(Some("led1", 1000))
+/// input = "led1"          Ok(None)
+/// input = "@ led1"        Err(ParseError::InvalidTimestamp)
+fn timestamp_tag<I: Input>(input: I) -> Result<Option<(I, u32)>, ParseError> {
+    const MARK: &[u8] = b"@";
+    const SPACE: &[u8] = b" ";
+
+    let Some(input) = input.strip_prefix(MARK) else {
+        return Ok(None);
+    };
+    let (input, at_tick) =
+        character::u32::<_, Error<_>>(input).map_err(|_| ParseError::InvalidTimestamp)?;
+    let (input, _) =
+        tag::<_, _, Error<_>>(SPACE)(input).map_err(|_| ParseError::InvalidTimestamp)?;
+    Ok(Some((input, at_tick)))
+}
+
+/// Same as `parse`, but for string input. Commands are ASCII, other input is rejected.
+pub fn parse_str(input: &str) -> Result<Command, ParseError> {
+    if !input.is_ascii() {
+        return Err(ParseError::NonAscii);
+    }
+    parse(input.as_bytes())
+}
+
+/// Same as `Command::from_slice`, for a `heapless::Vec` receive buffer.
+#[cfg(feature = "heapless")]
+pub fn parse_vec<const N: usize>(input: &heapless::Vec<u8, N>) -> Command {
+    Command::from_slice(input)
+}
+
+/// Parses a command addressing a named group of leds, e.g. "esp front on" with the group
+/// "front" made of Led1 and Led2. One command per led of the group is written into `out`,
+/// the number of written commands is returned.
+/// # To know:
+/// Unlike the led parser, the group name must match exactly. Nothing is written and 0 is
+/// returned if the group is unknown, the state is invalid, something else than blanks follows
+/// the command, or `out` cannot hold the whole group. The frame is checked like by `parse`,
+/// see `check_frame`.
+/// # Example
+/// groups = [("front", [Led1, Led2]), ("rear", [Led3, Led4])]
+/// in:                     out:
+/// input = "esp rear off"  2, [Command(Led3, Off), Command(Led4, Off)]
+/// input = "esp side off"  0
+pub fn parse_group(input: &[u8], groups: &[(&[u8], &[Led])], out: &mut [Command]) -> u32 {
+    const ESP: &[u8] = b"esp ";
+    const SPACE: &[u8] = b" ";
+
+    if check_frame(input).is_err() {
+        return 0;
+    }
+    let mut template = CommandBuilder::new().build();
+    let Ok(input) = command_prefix(input, ESP, &mut template) else {
+        return 0;
+    };
+    let Ok(input) = modifiers(input, &mut template) else {
+        return 0;
+    };
+    let Ok((input, name)) = take_till::<_, &[u8], Error<_>>(|byte| byte == b' ')(input) else {
+        return 0;
+    };
+    let Some((_, leds)) = groups.iter().find(|(group, _)| *group == name) else {
+        return 0;
+    };
+    let Ok((input, _)) = tag::<&[u8], &[u8], Error<_>>(SPACE)(input) else {
+        return 0;
+    };
+    let (input, latched) = hold_modifier(input);
+    let Ok((input, state)) = LedState::from_slice(input) else {
+        return 0;
+    };
+    let Ok((input, fade_in_ms)) = fade_in(input, state) else {
+        return 0;
+    };
+    let Ok((input, auto_off_ms)) = auto_off(input, state) else {
+        return 0;
+    };
+    if !trim(input).is_empty() {
+        return 0;
+    }
+    template.latched = latched;
+    template.auto_off_ms = auto_off_ms;
+    template.fade_in_ms = fade_in_ms;
+    if leds.len() > out.len() {
+        return 0;
+    }
+    for (command, led) in out.iter_mut().zip(leds.iter()) {
+        *command = Command {
+            led: *led,
+            state,
+            ..template
+        };
+    }
+    leds.len() as u32
+}
+
+/// Expands a user-configured shortcut before parsing, e.g. "panic" into
+/// "esp all blink 100". If the first token of the input is an alias of the table, it is
+/// replaced by its expansion, the rest of the input is kept. Otherwise the input is copied
+/// unchanged. Returns the length written into `out`, or None if it does not fit.
+/// # Example
+/// table = [("panic", "esp all blink 100")]
+/// in:                 out:
+/// input = "panic"     Some(17), "esp all blink 100"
+/// input = "esp led1 on"   Some(11), "esp led1 on"
+pub fn expand_aliases(input: &[u8], table: &[(&[u8], &[u8])], out: &mut [u8]) -> Option<usize> {
+    let mut writer = ByteWriter::new(out);
+    let found = table
+        .iter()
+        .find_map(|(alias, expansion)| Some((strip_token(input, alias)?, *expansion)));
+    match found {
+        Some((rest, expansion)) => {
+            writer.push(expansion)?;
+            writer.push(rest)?;
+        }
+        None => writer.push(input)?,
+    }
+    Some(writer.length)
+}
+
+/// Parses a command addressing a comma separated list of leds, e.g. "esp led1,l3 on".
+/// One command per led is written into `out`, in the order of the list, the number of
+/// written commands is returned. A single led is a list of one. Each command holds one led,
+/// the leds of the whole list are the joined `Command::target_mask` of the commands.
+/// # To know:
+/// A led given twice is rejected, so the list never holds more than `MAX_LED` leds.
+/// Instead of a list, "all" addresses every led, e.g. "esp all brightness 64" to dim the
+/// whole board, and "all~" followed by a led every led but that one. The
+/// shorter "!led2" is not accepted, "!" already starts a priority, see `priority_tag`.
+/// The frame is checked like by `parse`, see `check_frame`. Nothing is written on failure.
+/// # Example
+/// in:                         out:
+/// input = "esp led1,led2 on"  Ok(2), [Command(Led1, On), Command(Led2, On)]
+/// input = "esp led1,led1 on"  Err(ParseError::DuplicateTarget)
+/// input = "esp all~led2 on"   Ok(3), [Command(Led1, On), Command(Led3, On), Command(Led4, On)]
+/// input = "esp all on 64"     Ok(4), [Command(Led1, On(64)), .., Command(Led4, On(64))]
+pub fn parse_list(
+    input: &[u8],
+    out: &mut [Command; MAX_LED as usize],
+) -> Result<usize, ParseError> {
+    const ESP: &[u8] = b"esp ";
+    const SPACE: &[u8] = b" ";
+    const COMMA: u8 = b',';
+    const ALL: &[u8] = b"all";
+    const ALL_EXCEPT: &[u8] = b"all~";
+
+    check_frame(input)?;
+    let mut template = CommandBuilder::new().build();
+    let input = command_prefix(input, ESP, &mut template)?;
+    let input = modifiers(input, &mut template).map_err(|(error, _)| error)?;
+    let end = input
+        .iter()
+        .position(|byte| *byte == b' ')
+        .unwrap_or(input.len());
+    let (list, input) = input.split_at(end);
+    let mut leds = [Led::Led1; MAX_LED as usize];
+    let mut count = 0;
+    if list == ALL {
+        leds = LED_TOKENS.map(|(_, led)| led);
+        count = leds.len();
+    } else if let Some(excluded) = list.strip_prefix(ALL_EXCEPT) {
+        let (_, excluded) = Led::from_slice(excluded).map_err(|_| ParseError::UnknownLed)?;
+        for index in (1..=MAX_LED).filter_map(LedIndex::new) {
+            let led = Led::from_index(index);
+            if led != excluded {
+                leds[count] = led;
+                count += 1;
+            }
+        }
+    } else {
+        for token in list.split(|byte| *byte == COMMA) {
+            let (_, led) = Led::from_slice(token).map_err(|_| ParseError::UnknownLed)?;
+            if leds[..count].contains(&led) {
+                return Err(ParseError::DuplicateTarget);
+            }
+            leds[count] = led;
+            count += 1;
+        }
+    }
+    if input.is_empty() {
+        return Err(ParseError::MissingState);
+    }
+    let input = input.strip_prefix(SPACE).ok_or(ParseError::UnknownState)?;
+    let (input, latched) = hold_modifier(input);
+    let (input, state) = LedState::from_slice(input).map_err(|_| ParseError::UnknownState)?;
+    let (input, fade_in_ms) = fade_in(input, state)?;
+    let (input, auto_off_ms) = auto_off(input, state)?;
+    single_state(input).map_err(|(error, _)| error)?;
+    if !trim(input).is_empty() {
+        return Err(ParseError::TrailingInput);
+    }
+    template.latched = latched;
+    template.auto_off_ms = auto_off_ms;
+    template.fade_in_ms = fade_in_ms;
+    for (command, led) in out.iter_mut().zip(&leds[..count]) {
+        *command = Command {
+            led: *led,
+            state,
+            ..template
+        };
+    }
+    Ok(count)
+}
+
+/// Writes every basic command of a board with `led_count` leds into `out`, each led turned
+/// on and then off, Led1 first. Returns the number of written commands, `led_count * 2`.
+/// For test vectors and a "try all" diagnostic mode.
+/// # To know:
+/// A count over `MAX_LED` is treated as `MAX_LED`. Nothing is written and 0 is returned if
+/// `out` cannot hold all the commands.
+/// # Example
+/// in:             out:
+/// led_count = 1   2, [Command(Led1, On), Command(Led1, Off)]
+pub fn all_commands(led_count: u8, out: &mut [Command]) -> usize {
+    let count = usize::from(led_count.min(MAX_LED)) * 2;
+    let Some(out) = out.get_mut(..count) else {
+        return 0;
+    };
+    for (i, command) in out.iter_mut().enumerate() {
+        let led = Led::from_index(LedIndex(i as u8 / 2 + 1));
+        let state = if i % 2 == 0 {
+            LedState::ON
+        } else {
+            LedState::Off
+        };
+        *command = CommandBuilder::new().led(led).state(state).build();
+    }
+    count
+}
+
+/// Checks if a receive buffer still waits for the rest of its command. A command is
+/// complete once its terminator `\n` is received, everything before it can be parsed.
+/// # Example
+/// in:                     out:
+/// input = "esp led1"      true
+/// input = "esp led1 on\n" false
+pub fn is_partial(input: &[u8]) -> bool {
+    const TERMINATOR: u8 = b'\n';
+
+    !input.contains(&TERMINATOR)
+}
+
+/// Writes the wire form of a command into a buffer, keeping track of the written length.
+/// Every write returns None if the buffer is too small.
+/// Without a buffer, the bytes are only counted.
+struct ByteWriter<'a> {
+    out: Option<&'a mut [u8]>,
+    length: usize,
+}
+
+impl<'a> ByteWriter<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        ByteWriter {
+            out: Some(out),
+            length: 0,
+        }
+    }
+
+    /// Creates a writer without buffer, that only counts the bytes.
+    fn counter() -> Self {
+        ByteWriter {
+            out: None,
+            length: 0,
+        }
+    }
+
+    /// Appends the bytes as they are.
+    fn push(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.length.checked_add(bytes.len())?;
+        if let Some(out) = self.out.as_deref_mut() {
+            out.get_mut(self.length..end)?.copy_from_slice(bytes);
+        }
+        self.length = end;
+        Some(())
+    }
+
+    /// Appends a space followed by the token.
+    fn token(&mut self, token: &[u8]) -> Option<()> {
+        self.push(b" ")?;
+        self.push(token)
+    }
+
+    /// Appends the decimal digits of the value.
+    fn number(&mut self, value: u32) -> Option<()> {
+        let mut digits = [0u8; 10];
+        let mut pos = digits.len();
+        let mut value = value;
+        loop {
+            pos -= 1;
+            digits[pos] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[pos..])
+    }
+}
+
+/// Fluent API to construct commands programmatically, e.g. on host tooling.
+/// # Example
+/// CommandBuilder::new().led(Led::Led2).state(LedState::ON).build_bytes(&mut buf)
+/// writes "esp led2 on" into buf.
+pub struct CommandBuilder {
+    led: Led,
+    state: LedState,
+    repeat: u8,
+    priority: u8,
+    seq: u16,
+    at_tick: u32,
+    latched: bool,
+    addr: u8,
+    ack_requested: bool,
+    auto_off_ms: u16,
+    fade_in_ms: u16,
+}
+
+impl CommandBuilder {
+    /// Starts a builder targeting Led1 with the state Off, executed once and immediately with
+    /// normal priority and without sequence number, latched.
+    pub fn new() -> Self {
+        CommandBuilder {
+            led: Led::Led1,
+            state: LedState::Off,
+            repeat: 1,
+            priority: PRIORITY_NORMAL,
+            seq: 0,
+            at_tick: 0,
+            latched: true,
+            addr: 0,
+            ack_requested: false,
+            auto_off_ms: 0,
+            fade_in_ms: 0,
+        }
+    }
+
+    /// Sets the led to operate on.
+    pub fn led(mut self, led: Led) -> Self {
+        self.led = led;
+        self
+    }
+
+    /// Sets the state to put the led in.
+    pub fn state(mut self, state: LedState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Sets how many times the command should be executed.
+    pub fn repeat(mut self, repeat: u8) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Sets the priority of the command, see `Command::priority`.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the sequence number of the command, see `Command::seq`.
+    pub fn seq(mut self, seq: u16) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    /// Sets the tick to execute the command at, see `Command::at_tick`.
+    pub fn at_tick(mut self, at_tick: u32) -> Self {
+        self.at_tick = at_tick;
+        self
+    }
+
+    /// Sets whether the led keeps its state, see `Command::latched`.
+    pub fn latched(mut self, latched: bool) -> Self {
+        self.latched = latched;
+        self
+    }
+
+    /// Sets the address of the node the command is for, see `Command::addr`.
+    pub fn addr(mut self, addr: u8) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Sets whether the sender wants an acknowledgement, see `Command::ack_requested`.
+    pub fn ack_requested(mut self, ack_requested: bool) -> Self {
+        self.ack_requested = ack_requested;
+        self
+    }
+
+    /// Sets the milliseconds after which the led turns off again, see `Command::auto_off_ms`.
+    pub fn auto_off_ms(mut self, auto_off_ms: u16) -> Self {
+        self.auto_off_ms = auto_off_ms;
+        self
+    }
+
+    /// Sets the milliseconds the led takes to fade in, see `Command::fade_in_ms`.
+    pub fn fade_in_ms(mut self, fade_in_ms: u16) -> Self {
+        self.fade_in_ms = fade_in_ms;
+        self
+    }
+
+    /// Returns the successful command.
+    pub fn build(self) -> Command {
+        Command {
+            success: true,
+            led: self.led,
+            state: self.state,
+            kind: CommandKind::Set,
+            repeat: self.repeat,
+            priority: self.priority,
+            seq: self.seq,
+            at_tick: self.at_tick,
+            latched: self.latched,
+            addr: self.addr,
+            ack_requested: self.ack_requested,
+            auto_off_ms: self.auto_off_ms,
+            fade_in_ms: self.fade_in_ms,
+        }
+    }
+
+    /// Writes the canonical wire bytes of the command into the buffer, see `Command::to_bytes`.
+    pub fn build_bytes(self, out: &mut [u8]) -> Option<usize> {
+        self.build().to_bytes(out)
+    }
+}
+
+impl Default for CommandBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unsafe function that converts a pointer of bytes into a byte slice.
+/// Needed because slices are not FFI friendly. Potentially dangerous if
+/// a wrong length is give, but that must be handled on the C side.
+fn bytes_to_slice(input: *const u8, length: usize) -> &'static [u8] {
+    unsafe { from_raw_parts(input, length) }
+}
+
+/// C FFI. Converts the ASCII stream into a usable command.
+/// # To know:
+/// `length` is an upper bound, e.g. the capacity of the serial buffer. The command ends at
+/// the first '\n' or NUL within it, the bytes after the terminator are not looked at.
+#[no_mangle]
+pub extern "C" fn parse_uart(input: *const u8, length: usize) -> Command {
+    let input = bytes_to_slice(input, length);
+    let end = input
+        .iter()
+        .position(|byte| matches!(byte, b'\n' | 0))
+        .unwrap_or(input.len());
+    Command::from_slice(&input[..end])
+}
+
+/// Last known state of every led, true meaning on. Reported by `fill_status`.
+static LED_STATES: [AtomicBool; MAX_LED as usize] =
+    [const { AtomicBool::new(false) }; MAX_LED as usize];
+
+/// C FFI. Records the state a led was put in, so that it can be reported by `fill_status`.
+#[no_mangle]
+pub extern "C" fn set_status(led: Led, state: LedState) {
+    if state == LedState::Toggle {
+        LED_STATES[led as usize].fetch_xor(true, Ordering::Relaxed);
+        return;
+    }
+    let on = matches!(state, LedState::On { .. });
+    LED_STATES[led as usize].store(on, Ordering::Relaxed);
+}
+
+/// C FFI. Answers a status command by writing the recorded state of every led into `out`,
+/// Led1 first. At most `len` states are written, the number of written states is returned.
+/// Nothing is written if `out` is null.
+#[no_mangle]
+pub extern "C" fn fill_status(out: *mut LedState, len: u32) -> u32 {
+    if out.is_null() {
+        return 0;
+    }
+    let count = LED_STATES.len().min(len as usize);
+    let out = states_to_slice(out, count);
+    for (state, on) in out.iter_mut().zip(LED_STATES.iter()) {
+        *state = if on.load(Ordering::Relaxed) {
+            LedState::ON
+        } else {
+            LedState::Off
+        };
+    }
+    count as u32
+}
+
+/// Unsafe function that converts a pointer of states into a mutable slice.
+/// Same as `bytes_to_slice`, the length must be handled on the C side.
+fn states_to_slice(out: *mut LedState, length: usize) -> &'static mut [LedState] {
+    unsafe { from_raw_parts_mut(out, length) }
+}
+
+/// What a word of a command is recognized as, see `Command::tokenize`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+    /// The keyword "esp", possibly with an address.
+    Prefix,
+    /// A modifier, e.g. "3x", "!hi" or "hold".
+    Modifier,
+    /// The keyword of a command without led, e.g. "status" or "mask".
+    Keyword,
+    /// A led, e.g. "led1".
+    Led,
+    /// The keyword of a state, e.g. "on" or "blink".
+    State,
+    /// A value after a state or command keyword, e.g. "500" or "red".
+    Argument,
+    /// A word that is not recognized at its position.
+    Unknown,
+}
+
+/// A word of a command with what it is recognized as, see `Command::tokenize`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// The word in the input.
+    pub text: &'a [u8],
+}
+
+/// Iterates over the commands contained in a buffer holding several of them.
+/// Commands are separated by `\n` or `;`, surrounding blanks are ignored.
+/// # To know:
+/// Empty segments and comment lines (lines whose first non-blank byte is `#`)
+/// are skipped entirely, they are not yielded as failed commands.
+/// # Example
+/// input = "# set up\nesp led1 on; esp led2 off\n"
+/// yields: Command(Led1, On), Command(Led2, Off)
+pub struct CommandStream<'a> {
+    segments: Segments<'a>,
+}
+
+impl<'a> CommandStream<'a> {
+    /// Creates a stream over the given buffer.
+    pub fn new(input: &'a [u8]) -> Self {
+        CommandStream {
+            segments: Segments::new(input),
+        }
+    }
+
+    /// Points the stream at a fresh buffer, e.g. the next DMA buffer, as if it was created
+    /// with `new`. Nothing of the previous buffer is kept, a line it left open does not
+    /// carry over.
+    pub fn reset(&mut self, input: &'a [u8]) {
+        self.segments = Segments::new(input);
+    }
+}
+
+impl Iterator for CommandStream<'_> {
+    type Item = Command;
+
+    fn next(&mut self) -> Option<Command> {
+        self.segments.next().map(Command::from_slice)
+    }
+}
+
+/// The commands of a buffer, parsed once like `CommandStream` does into a fixed array of
+/// `N` commands. For buffers that are gone through several times, no allocation is needed.
+/// # To know:
+/// Commands beyond the capacity are dropped, `is_truncated` tells if that happened.
+/// # Example
+/// CommandBatch::<4>::parse(b"esp led1 on; esp led2 off").as_slice()
+/// gives [Command(Led1, On), Command(Led2, Off)]
+pub struct CommandBatch<const N: usize> {
+    commands: [Command; N],
+    length: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> CommandBatch<N> {
+    /// Parses every command of the buffer, as far as the capacity allows.
+    pub fn parse(input: &[u8]) -> Self {
+        let mut batch = CommandBatch {
+            commands: [Command::safe_default(); N],
+            length: 0,
+            truncated: false,
+        };
+        for command in CommandStream::new(input) {
+            let Some(slot) = batch.commands.get_mut(batch.length) else {
+                batch.truncated = true;
+                break;
+            };
+            *slot = command;
+            batch.length += 1;
+        }
+        batch
+    }
+
+    /// Returns the parsed commands, in the order of the buffer.
+    pub fn as_slice(&self) -> &[Command] {
+        &self.commands[..self.length]
+    }
+
+    /// Checks if the buffer held more than `N` commands, the others were dropped.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// Splits a buffer into its command segments, see `Command::segments`.
+struct Segments<'a> {
+    input: &'a [u8],
+    at_line_start: bool,
+}
+
+impl<'a> Segments<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Segments {
+            input,
+            at_line_start: true,
+        }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        const NEWLINE: u8 = b'\n';
+        const SEMICOLON: u8 = b';';
+        const COMMENT: u8 = b'#';
+
+        while !self.input.is_empty() {
+            // A comment runs until the end of the line, even across a ';'.
+            if self.at_line_start && trim(self.input).first() == Some(&COMMENT) {
+                self.input = match self.input.iter().position(|byte| *byte == NEWLINE) {
+                    Some(pos) => &self.input[(pos + 1)..],
+                    None => &[],
+                };
+                continue;
+            }
+            let end = self
+                .input
+                .iter()
+                .position(|byte| *byte == NEWLINE || *byte == SEMICOLON)
+                .unwrap_or(self.input.len());
+            let segment = trim(&self.input[..end]);
+            self.at_line_start = self.input.get(end) != Some(&SEMICOLON);
+            self.input = self.input.get((end + 1)..).unwrap_or(&[]);
+            if !segment.is_empty() {
+                return Some(segment);
+            }
+        }
+        None
+    }
+}
+
+/// Parses a buffer holding several commands, split like `CommandStream` does, into `out`.
+/// Every segment gets its own result, a malformed one does not stop the others from being
+/// parsed. Returns the number of written results. Segments that do not fit into `out` are
+/// dropped.
+/// # Example
+/// in:                                 out:
+/// input = "esp led1 on; esp led2 off" 2, [Ok(Command(Led1, On)), Ok(Command(Led2, Off))]
+/// input = "esp led1 on; esp led9 on"  2, [Ok(Command(Led1, On)), Err(ParseError::UnknownLed)]
+pub fn parse_multi(input: &[u8], out: &mut [Result<Command, ParseError>]) -> usize {
+    let mut count = 0;
+    for (slot, segment) in out.iter_mut().zip(Segments::new(input)) {
+        *slot = parse(segment);
+        count += 1;
+    }
+    count
+}
+
+/// Checks that every command of a buffer, split like `CommandStream` does, parses, for
+/// batches that are applied all or nothing. Nothing is applied here. Returns the number of
+/// commands, or the index of the first bad command, 0 being the first one, and its error.
+/// # Example
+/// in:                                 out:
+/// input = "esp led1 on; esp led2 off" Ok(2)
+/// input = "esp led1 on; esp led9 on"  Err((1, ParseError::UnknownLed))
+pub fn validate_all(input: &[u8]) -> Result<usize, (usize, ParseError)> {
+    let mut count = 0;
+    for segment in Segments::new(input) {
+        parse(segment).map_err(|error| (count, error))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes the canonical wire form of several commands into the buffer, separated by `;`.
+/// The opposite of `parse_multi`. Returns the number of written bytes, or None if a
+/// command was not parsed successfully or the buffer is too small.
+/// # Example
+/// cmds = [Command(Led1, On), Command(Led2, Off)]
+/// writes "esp led1 on;esp led2 off"
+pub fn encode_multi(cmds: &[Command], out: &mut [u8]) -> Option<usize> {
+    const SEPARATOR: &[u8] = b";";
+
+    let mut writer = ByteWriter::new(out);
+    for (i, command) in cmds.iter().enumerate() {
+        if i > 0 {
+            writer.push(SEPARATOR)?;
+        }
+        command.write_to(&mut writer)?;
+    }
+    Some(writer.length)
+}
+
+/// Removes a UTF-8 byte order mark (EF BB BF) that some terminals prepend to the first
+/// line, so the first command after connecting is not lost. The end of a mark split by a
+/// lost first byte (BB BF or BF) is removed as well. Opt-in, the parsers do not call it,
+/// as it could mask real corruption.
+/// # Example
+/// in:                             out:
+/// input = "\xEF\xBB\xBFesp led1 on"  "esp led1 on"
+/// input = "\xBB\xBFesp led1 on"      "esp led1 on"
+/// input = "esp led1 on"           "esp led1 on"
+pub fn strip_bom(input: &[u8]) -> &[u8] {
+    const BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+    (0..BOM.len())
+        .find_map(|start| input.strip_prefix(&BOM[start..]))
+        .unwrap_or(input)
+}
+
+/// Resolves the backspaces (0x08 or DEL) typed into an interactive terminal, each removes
+/// the byte before it. Writes the edited line into `out` and returns its length, or None
+/// if the edited line does not fit into `out`. Opt-in, the parsers reject the line as
+/// `ParseError::ControlChar` otherwise.
+/// # To know:
+/// A backspace at the start of the line does nothing. Only the edited line needs to fit,
+/// a byte that is erased again may go past the end of `out`.
+/// # Example
+/// in:                                 out:
+/// input = "esp led2\x7f1 on"          Some(11), "esp led1 on"
+/// input = "\x08esp led1 on"           Some(11), "esp led1 on"
+/// input = "abcd\x7f", 3 bytes of out  Some(3), "abc"
+/// input = "abcd", 3 bytes of out      None
+pub fn apply_line_edits(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    const BACKSPACE: u8 = 0x08;
+    const DELETE: u8 = 0x7F;
+
+    // Counts the bytes that do not fit as well, so that a backspace erases the right one.
+    let mut length: usize = 0;
+    for byte in input {
+        match *byte {
+            BACKSPACE | DELETE => length = length.saturating_sub(1),
+            byte => {
+                if let Some(slot) = out.get_mut(length) {
+                    *slot = byte;
+                }
+                length += 1;
+            }
+        }
+    }
+    (length <= out.len()).then_some(length)
+}
+
+/// Removes leading and trailing blanks (space, tab, carriage return) from a slice.
+fn trim<I: Input>(input: I) -> I {
+    let is_blank = |byte: u8| matches!(byte, b' ' | b'\t' | b'\r');
+    let start = input
+        .position(|byte| !is_blank(byte))
+        .unwrap_or(input.len());
+    let end = input
+        .iter_indices()
+        .filter(|(_, byte)| !is_blank(*byte))
+        .last()
+        .map_or(start, |(pos, _)| pos + 1);
+    input.slice(start..end)
+}
+
+/// Diagnostic parse for host tooling, reporting which part of the grammar failed and where.
+#[cfg(feature = "alloc")]
+mod diagnostic {
+    use super::*;
+    use alloc::string::String;
+    use nom::bytes::complete::tag_no_case;
+    use nom::error::{context, convert_error, ErrorKind, ParseError as _, VerboseError};
+
+    type DiagnosticResult<'a, O> = IResult<&'a str, O, VerboseError<&'a str>>;
+
+    /// Runs a step of the byte grammar on str input, so it can be used with nom's context.
+    fn step<'a, O>(
+        mut parser: impl FnMut(&'a [u8]) -> Option<(&'a [u8], O)>,
+    ) -> impl FnMut(&'a str) -> DiagnosticResult<'a, O> {
+        move |input: &'a str| {
+            let error = || nom::Err::Error(VerboseError::from_error_kind(input, ErrorKind::Verify));
+            let (rest, output) = parser(input.as_bytes()).ok_or_else(error)?;
+            let rest = input.get((input.len() - rest.len())..).ok_or_else(error)?;
+            Ok((rest, output))
+        }
+    }
+
+    /// Same grammar as `parse`, with every step wrapped in a nom context.
+    fn command(input: &str) -> DiagnosticResult<'_, Command> {
+        const ESP: &str = "esp ";
+        const SPACE: &str = " ";
+
+        let mut command = CommandBuilder::new().build();
+        if is_help(input.as_bytes(), ESP.as_bytes()) {
+            command.kind = CommandKind::Help;
+            return Ok((&input[input.len()..], command));
+        }
+        let address = step(|input| address_prefix(input, ESP.as_bytes()).ok().flatten());
+        let input = match context("address", address)(input) {
+            Ok((input, addr)) => {
+                command.addr = addr;
+                input
+            }
+            Err(_) => context("keyword \"esp \"", tag_no_case(ESP))(input)?.0,
+        };
+        let (input, ()) = context(
+            "modifiers",
+            step(|input| Some((modifiers(input, &mut command).ok()?, ()))),
+        )(input)?;
+        let keyword = |input| {
+            if let Some((input, bits)) = mask_command(input).ok()? {
+                return Some((input, CommandKind::Mask { bits }));
+            }
+            if let Some((input, code)) = fault_command(input).ok()? {
+                return Some((input, CommandKind::Fault { code }));
+            }
+            if let Some((input, step_ms)) = cycle_command(input).ok()? {
+                return Some((input, CommandKind::Cycle { step_ms }));
+            }
+            match define_group(input).ok()? {
+                Some(found) => Some(found),
+                None => CommandKind::from_keyword(input),
+            }
+        };
+        let input = match step(keyword)(input) {
+            Ok((input, kind)) => {
+                command.kind = kind;
+                input
+            }
+            Err(_) => {
+                let (input, led) =
+                    context("led", step(|input| Led::from_slice(input).ok()))(input)?;
+                let (input, _) = context("space before the state", tag(SPACE))(input)?;
+                let (input, latched) = step(|input| Some(hold_modifier(input)))(input)?;
+                let (input, state) =
+                    context("state", step(|input| LedState::from_slice(input).ok()))(input)?;
+                let (input, fade_in_ms) =
+                    context("fade-in", step(|input| fade_in(input, state).ok()))(input)?;
+                let (input, auto_off_ms) =
+                    context("auto-off", step(|input| auto_off(input, state).ok()))(input)?;
+                command.led = led;
+                command.state = state;
+                command.latched = latched;
+                command.auto_off_ms = auto_off_ms;
+                command.fade_in_ms = fade_in_ms;
+                input
+            }
+        };
+        let (input, ()) = context(
+            "end of the command",
+            step(|input| {
+                trim(input)
+                    .is_empty()
+                    .then_some((&input[input.len()..], ()))
+            }),
+        )(input)?;
+        Ok((input, command))
+    }
+
+    /// Parses a command like `parse`, but describes a failure in a human-readable way:
+    /// every step of the grammar that failed, with a marker at the failing position.
+    /// # Example
+    /// input = "esp led1 wfea"
+    /// Err("0: at line 1, in Verify: ... 1: at line 1, in state: ...")
+    pub fn diagnose(input: &str) -> Result<Command, String> {
+        match command(input) {
+            Ok((_, command)) => Ok(command),
+            Err(nom::Err::Error(error) | nom::Err::Failure(error)) => {
+                Err(convert_error(input, error))
+            }
+            Err(nom::Err::Incomplete(_)) => Err(String::from("incomplete input")),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+pub use diagnostic::diagnose;
+
+/// Parser health counters for on-target profiling, reported over the firmware's telemetry.
+#[cfg(feature = "metrics")]
+mod metrics {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    /// Number of successfully parsed commands.
+    static PARSE_OK: AtomicU32 = AtomicU32::new(0);
+    /// Number of commands that failed to parse.
+    static PARSE_FAIL: AtomicU32 = AtomicU32::new(0);
+    /// Number of parsed commands, successful or not.
+    static PARSE_TOTAL: AtomicU32 = AtomicU32::new(0);
+
+    /// Counts a parsed command, called by `Command::from_input`.
+    pub(crate) fn record(success: bool) {
+        let counter = if success { &PARSE_OK } else { &PARSE_FAIL };
+        counter.fetch_add(1, Ordering::Relaxed);
+        PARSE_TOTAL.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of successful, failed and total parses, in that order, since
+    /// start-up. The counters wrap around.
+    pub fn parse_stats() -> (u32, u32, u32) {
+        (
+            PARSE_OK.load(Ordering::Relaxed),
+            PARSE_FAIL.load(Ordering::Relaxed),
+            PARSE_TOTAL.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use metrics::parse_stats;
+
+/// Testing aid for the firmware logic around `parse_uart`, not meant for production.
+#[cfg(any(test, feature = "test-util"))]
+mod test_util {
+    use super::*;
+
+    /// Records the commands parsed from the fed frames, up to `N` of them, so a test can
+    /// assert the sequence.
+    /// # Example
+    /// let mut sink = RecordingSink::<4>::new();
+    /// sink.feed(b"esp led1 on");
+    /// sink.commands() == [Command(Led1, On)]
+    pub struct RecordingSink<const N: usize> {
+        commands: [Command; N],
+        length: usize,
+    }
+
+    impl<const N: usize> RecordingSink<N> {
+        /// Creates an empty sink.
+        pub fn new() -> Self {
+            RecordingSink {
+                commands: [Command::safe_default(); N],
+                length: 0,
+            }
+        }
+
+        /// Parses the frame through the FFI like the firmware does and records the command.
+        /// Returns the command, which is not recorded if the sink is full.
+        pub fn feed(&mut self, frame: &[u8]) -> Command {
+            let command = parse_uart(frame.as_ptr(), frame.len());
+            self.record(command);
+            command
+        }
+
+        /// Records the command. Returns false if the sink is full, the command is dropped.
+        pub fn record(&mut self, command: Command) -> bool {
+            let Some(slot) = self.commands.get_mut(self.length) else {
+                return false;
+            };
+            *slot = command;
+            self.length += 1;
+            true
+        }
+
+        /// The recorded commands, oldest first.
+        pub fn commands(&self) -> &[Command] {
+            &self.commands[..self.length]
+        }
+    }
+
+    impl<const N: usize> Default for RecordingSink<N> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use test_util::RecordingSink;
+
+/// Not sure how to handle a panic.
+/// With `std`, its own handler is used.
+#[cfg(not(any(test, feature = "std")))]
+#[panic_handler]
+fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// The command the parser returns for a successful led command.
+    fn set_command(led: Led, state: LedState) -> Command {
+        Command {
+            success: true,
+            led,
+            state,
+            kind: CommandKind::Set,
+            repeat: 1,
+            priority: PRIORITY_NORMAL,
+            seq: 0,
+            at_tick: 0,
+            latched: true,
+            addr: 0,
+            ack_requested: false,
+            auto_off_ms: 0,
+            fade_in_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_led1_on() {
+        const LED1_ON_COMMAND: &str = "esp led1 on";
+        assert_eq!(
+            parse_uart(LED1_ON_COMMAND.as_ptr(), LED1_ON_COMMAND.len()),
+            Command {
+                success: true,
+                led: Led::Led1,
+                state: LedState::ON,
+                kind: CommandKind::Set,
+                repeat: 1,
+                priority: PRIORITY_NORMAL,
+                seq: 0,
+                at_tick: 0,
+                latched: true,
+                addr: 0,
+                ack_requested: false,
+                auto_off_ms: 0,
+                fade_in_ms: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_led2_off() {
+        const LED2_OFF_COMMAND: &str = "esp led2 off";
+        assert_eq!(
+            parse_uart(LED2_OFF_COMMAND.as_ptr(), LED2_OFF_COMMAND.len()),
+            Command {
+                success: true,
+                led: Led::Led2,
+                state: LedState::Off,
+                kind: CommandKind::Set,
+                repeat: 1,
+                priority: PRIORITY_NORMAL,
+                seq: 0,
+                at_tick: 0,
+                latched: true,
+                addr: 0,
+                ack_requested: false,
+                auto_off_ms: 0,
+                fade_in_ms: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_led2_off_fail() {
+        const LED2_FAIL_COMMAND: &str = "esp led2 wfea";
+        assert_eq!(
+            parse_uart(LED2_FAIL_COMMAND.as_ptr(), LED2_FAIL_COMMAND.len()),
+            Command {
+                success: false,
+                led: Led::Led1,
+                state: LedState::Off,
+                kind: CommandKind::Clear,
+                repeat: 1,
+                priority: PRIORITY_NORMAL,
+                seq: 0,
+                at_tick: 0,
+                latched: true,
+                addr: 0,
+                ack_requested: false,
+                auto_off_ms: 0,
+                fade_in_ms: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stream_skips_comments() {
+        const SCRIPT: &[u8] =
+            b"# turn on the first led\nesp led1 on\n   # then the second one\nesp led2 off\n";
+        let mut stream = CommandStream::new(SCRIPT);
+        assert_eq!(stream.next(), Some(set_command(Led::Led1, LedState::ON)));
+        assert_eq!(stream.next(), Some(set_command(Led::Led2, LedState::Off)));
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    fn test_stream_comment_and_failures() {
+        // The ';' inside the comment does not start a new command, the invalid command is still yielded.
+        const SCRIPT: &[u8] = b"#esp led3 on; esp led4 on\r\n\nesp led3 on; esp led4 wfea;;\n#";
+        let mut stream = CommandStream::new(SCRIPT);
+        assert_eq!(stream.next().map(|command| command.led), Some(Led::Led3));
+        assert_eq!(stream.next().map(|command| command.success), Some(false));
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    fn test_builder_round_trip() {
+        let mut buf = [0u8; 16];
+        let length = CommandBuilder::new()
+            .led(Led::Led2)
+            .state(LedState::ON)
+            .build_bytes(&mut buf)
+            .unwrap();
+        assert_eq!(&buf[..length], b"esp led2 on");
+        assert_eq!(
+            Command::from_slice(&buf[..length]),
+            CommandBuilder::new()
+                .led(Led::Led2)
+                .state(LedState::ON)
+                .build()
+        );
+
+        let length = CommandBuilder::new()
+            .led(Led::Led4)
+            .build_bytes(&mut buf)
+            .unwrap();
+        assert_eq!(
+            Command::from_slice(&buf[..length]),
+            set_command(Led::Led4, LedState::Off)
+        );
+    }
+
+    #[test]
+    fn test_builder_buffer_too_small() {
+        let mut buf = [0u8; 10];
+        assert_eq!(
+            CommandBuilder::new()
+                .state(LedState::Off)
+                .build_bytes(&mut buf),
+            None
+        );
+    }
+
+    #[test]
+    fn test_short_led_token() {
+        const L1_ON_COMMAND: &str = "esp l1 on";
+        assert_eq!(
+            parse_uart(L1_ON_COMMAND.as_ptr(), L1_ON_COMMAND.len()),
+            set_command(Led::Led1, LedState::ON)
+        );
+        const L4_OFF_COMMAND: &str = "esp l4 off";
+        assert_eq!(
+            parse_uart(L4_OFF_COMMAND.as_ptr(), L4_OFF_COMMAND.len()),
+            set_command(Led::Led4, LedState::Off)
+        );
+        const L5_ON_COMMAND: &str = "esp l5 on";
+        assert!(!parse_uart(L5_ON_COMMAND.as_ptr(), L5_ON_COMMAND.len()).success);
+    }
+
+    #[test]
+    fn test_parse_str() {
+        assert_eq!(
+            parse_str("esp led3 on"),
+            Ok(set_command(Led::Led3, LedState::ON))
+        );
+        assert_eq!(parse_str("esp led3"), Err(ParseError::MissingState));
+        assert_eq!(parse_str("esp led3 wfea"), Err(ParseError::UnknownState));
+        assert_eq!(parse_str("esp led9 on"), Err(ParseError::UnknownLed));
+        assert_eq!(parse_str("led3 on"), Err(ParseError::MissingPrefix));
+    }
+
+    #[test]
+    fn test_parse_str_non_ascii() {
+        assert_eq!(parse_str("esp led1 \u{f6}n"), Err(ParseError::NonAscii));
+    }
+
+    #[test]
+    fn test_tokens_at_slice_end() {
+        // The tokens end exactly at the end of the slice, a longer buffer is cut right after them.
+        const BUFFER: &[u8] = b"esp led4 offXXXX";
+        assert_eq!(
+            Command::from_slice(&BUFFER[..12]),
+            set_command(Led::Led4, LedState::Off)
+        );
+        assert_eq!(parse(&BUFFER[..8]), Err(ParseError::MissingState));
+        assert_eq!(parse(&BUFFER[..7]), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(&BUFFER[..10]), Err(ParseError::UnknownState));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut buf = [0u8; 16];
+        let command = parse(b"esp status").unwrap();
+        assert_eq!(command.kind, CommandKind::Status);
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp status");
+        assert_eq!(parse(b"esp statusx"), Err(ParseError::UnknownCommand));
+        assert_eq!(
+            parse(b"esp status\r").map(|command| command.kind),
+            Ok(CommandKind::Status)
+        );
+        assert_eq!(
+            parse(b"esp status\t").map(|command| command.kind),
+            Ok(CommandKind::Status)
+        );
+        assert_eq!(
+            parse_uart(b"esp status\r\n".as_ptr(), 12).kind,
+            CommandKind::Status
+        );
+        assert_eq!(
+            Command::classify(b"esp status\r"),
+            Some(CommandKind::Status)
+        );
+    }
+
+    #[test]
+    fn test_fill_status() {
+        set_status(Led::Led2, LedState::ON);
+        set_status(Led::Led3, LedState::ON);
+        set_status(Led::Led3, LedState::Off);
+        let mut states = [LedState::ON; 4];
+        assert_eq!(fill_status(states.as_mut_ptr(), 4), 4);
+        assert_eq!(
+            states,
+            [LedState::Off, LedState::ON, LedState::Off, LedState::Off]
+        );
+
+        // Only the requested length is written.
+        let mut states = [LedState::ON; 4];
+        assert_eq!(fill_status(states.as_mut_ptr(), 1), 1);
+        assert_eq!(
+            states,
+            [LedState::Off, LedState::ON, LedState::ON, LedState::ON]
+        );
+        assert_eq!(fill_status(core::ptr::null_mut(), 4), 0);
+    }
+
+    #[test]
+    fn test_repeat_prefix() {
+        let mut buf = [0u8; 16];
+        let command = parse(b"esp 3x led1 on").unwrap();
+        assert_eq!(
+            command,
+            Command {
+                repeat: 3,
+                ..set_command(Led::Led1, LedState::ON)
+            }
+        );
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp 3x led1 on");
+        assert_eq!(parse(b"esp led1 on").unwrap().repeat, 1);
+        assert_eq!(parse(b"esp 2x status").unwrap().repeat, 2);
+    }
+
+    #[test]
+    fn test_repeat_prefix_invalid() {
+        // A count of 0 is rejected, see repeat_prefix.
+        assert_eq!(parse(b"esp 0x led1 on"), Err(ParseError::InvalidRepeat));
+        assert_eq!(parse(b"esp 256x led1 on"), Err(ParseError::InvalidRepeat));
+        assert_eq!(parse(b"esp 3xled1 on"), Err(ParseError::InvalidRepeat));
+    }
+
+    /// Simulates the UART receiving a command one byte at a time.
+    mod uart_feed {
+        use super::*;
+
+        /// Receive buffer that grows with every byte, as in the firmware's interrupt.
+        struct UartFeed {
+            buffer: [u8; 32],
+            length: usize,
+        }
+
+        impl UartFeed {
+            fn new() -> Self {
+                UartFeed {
+                    buffer: [0; 32],
+                    length: 0,
+                }
+            }
+
+            /// Receives one byte. Once the command is complete, it is parsed without its
+            /// terminator and the buffer starts over.
+            fn receive(&mut self, byte: u8) -> Option<Result<Command, ParseError>> {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+                let received = &self.buffer[..self.length];
+                if is_partial(received) {
+                    return None;
+                }
+                let result = parse(&received[..(self.length - 1)]);
+                self.length = 0;
+                Some(result)
+            }
+        }
+
+        /// Feeds the bytes one by one. Returns the parse result and the index of the byte
+        /// that completed the command.
+        fn feed(feed: &mut UartFeed, bytes: &[u8]) -> Option<(usize, Result<Command, ParseError>)> {
+            bytes
+                .iter()
+                .enumerate()
+                .find_map(|(i, byte)| feed.receive(*byte).map(|result| (i, result)))
+        }
+
+        #[test]
+        fn test_feed_single_command() {
+            const BYTES: &[u8] = b"esp led3 on\n";
+            let mut uart = UartFeed::new();
+            assert_eq!(
+                feed(&mut uart, BYTES),
+                Some((BYTES.len() - 1, Ok(set_command(Led::Led3, LedState::ON))))
+            );
+            // Without the terminator, nothing is parsed.
+            assert_eq!(feed(&mut uart, b"esp led4 off"), None);
+            assert_eq!(
+                feed(&mut uart, b"\n"),
+                Some((0, Ok(set_command(Led::Led4, LedState::Off))))
+            );
+        }
+
+        #[test]
+        fn test_feed_malformed_command() {
+            const BYTES: &[u8] = b"esp led3 wfea\nesp led1 on\n";
+            let mut uart = UartFeed::new();
+            assert_eq!(
+                feed(&mut uart, BYTES),
+                Some((13, Err(ParseError::UnknownState)))
+            );
+            // The next command starts on a clean buffer.
+            assert_eq!(
+                feed(&mut uart, &BYTES[14..]),
+                Some((11, Ok(set_command(Led::Led1, LedState::ON))))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_group() {
+        const GROUPS: &[(&[u8], &[Led])] = &[
+            (b"front", &[Led::Led1, Led::Led2]),
+            (b"rear", &[Led::Led3, Led::Led4]),
+        ];
+        let mut commands = [set_command(Led::Led1, LedState::Off); 4];
+        assert_eq!(parse_group(b"esp front on", GROUPS, &mut commands), 2);
+        assert_eq!(
+            commands[..2],
+            [
+                set_command(Led::Led1, LedState::ON),
+                set_command(Led::Led2, LedState::ON)
+            ]
+        );
+        // A group that does not fit is not written partially.
+        assert_eq!(parse_group(b"esp rear on", GROUPS, &mut commands[..1]), 0);
+        assert_eq!(commands[0], set_command(Led::Led1, LedState::ON));
+    }
+
+    #[test]
+    fn test_parse_group_unknown() {
+        const GROUPS: &[(&[u8], &[Led])] = &[(b"front", &[Led::Led1, Led::Led2])];
+        let mut commands = [set_command(Led::Led1, LedState::Off); 4];
+        assert_eq!(parse_group(b"esp side on", GROUPS, &mut commands), 0);
+        assert_eq!(parse_group(b"esp fronts on", GROUPS, &mut commands), 0);
+        assert_eq!(parse_group(b"esp front wfea", GROUPS, &mut commands), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_command_layout() {
+        use core::mem::{offset_of, size_of};
+
+        // The C code reads the command by value, see my_header.h. Any change of these values
+        // breaks the ABI and requires the header to be regenerated.
+        const _: () = assert!(size_of::<Command>() == 68);
+        const _: () = assert!(offset_of!(Command, success) == 0);
+        const _: () = assert!(offset_of!(Command, led) == 4);
+        const _: () = assert!(offset_of!(Command, state) == 8);
+        const _: () = assert!(offset_of!(Command, kind) == 20);
+        const _: () = assert!(offset_of!(Command, repeat) == 52);
+        const _: () = assert!(offset_of!(Command, priority) == 53);
+        const _: () = assert!(offset_of!(Command, seq) == 54);
+        const _: () = assert!(offset_of!(Command, at_tick) == 56);
+        const _: () = assert!(offset_of!(Command, latched) == 60);
+        const _: () = assert!(offset_of!(Command, addr) == 61);
+        const _: () = assert!(offset_of!(Command, ack_requested) == 62);
+        const _: () = assert!(offset_of!(Command, auto_off_ms) == 64);
+        const _: () = assert!(offset_of!(Command, fade_in_ms) == 66);
+    }
+
+    #[test]
+    fn test_priority_tag() {
+        let mut buf = [0u8; 24];
+        let command = parse(b"esp !hi led1 on").unwrap();
+        assert_eq!(
+            command,
+            Command {
+                priority: PRIORITY_HIGH,
+                ..set_command(Led::Led1, LedState::ON)
+            }
+        );
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp !hi led1 on");
+        assert_eq!(parse(b"esp led1 on").unwrap().priority, PRIORITY_NORMAL);
+        assert_eq!(parse(b"esp !lo status").unwrap().priority, PRIORITY_LOW);
+        assert_eq!(parse(b"esp !mid led1 on"), Err(ParseError::InvalidPriority));
+    }
+
+    #[test]
+    fn test_modifiers_any_order() {
+        let expected = Command {
+            repeat: 2,
+            priority: PRIORITY_LOW,
+            ..set_command(Led::Led4, LedState::Off)
+        };
+        assert_eq!(parse(b"esp 2x !lo led4 off"), Ok(expected));
+        assert_eq!(parse(b"esp !lo 2x led4 off"), Ok(expected));
+    }
+
+    #[test]
+    fn test_from_framed() {
+        assert_eq!(
+            Command::from_framed(b"\x02esp led2 on\x03"),
+            set_command(Led::Led2, LedState::ON)
+        );
+        assert!(!Command::from_framed(b"\x02esp led2 on").success);
+        assert!(!Command::from_framed(b"esp led2 on\x03").success);
+        assert!(!Command::from_framed(b"\x02").success);
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_blink_duration_units() {
+        let blink = |period_ms| set_command(Led::Led1, LedState::Blink { period_ms });
+        assert_eq!(parse(b"esp led1 blink 500ms"), Ok(blink(500)));
+        assert_eq!(parse(b"esp led1 blink 500"), Ok(blink(500)));
+        assert_eq!(parse(b"esp led1 blink 2s"), Ok(blink(2000)));
+        assert_eq!(parse(b"esp led1 blink 65s"), Ok(blink(65000)));
+        assert_eq!(parse(b"esp led1 blink s"), Err(ParseError::UnknownState));
+        // 66 s overflows the u16 only after scaling.
+        assert_eq!(parse(b"esp led1 blink 66s"), Err(ParseError::UnknownState));
+        assert_eq!(
+            parse(b"esp led1 blink 500us"),
+            Err(ParseError::UnknownState)
+        );
+
+        let mut buf = [0u8; 24];
+        let length = blink(2000).to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 blink 2000");
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        assert_eq!(
+            parse_lenient(b"esp led1 on extra junk"),
+            Ok((set_command(Led::Led1, LedState::ON), true))
+        );
+        assert_eq!(
+            parse_lenient(b"esp led1 on"),
+            Ok((set_command(Led::Led1, LedState::ON), false))
+        );
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            parse_lenient(b"esp led1 blink 2s \r"),
+            Ok((
+                set_command(Led::Led1, LedState::Blink { period_ms: 2000 }),
+                false
+            ))
+        );
+        assert_eq!(
+            parse_lenient(b"esp led1 wfea"),
+            Err(ParseError::UnknownState)
+        );
+    }
+
+    #[test]
+    fn test_trailing_input_strict() {
+        assert_eq!(
+            parse(b"esp led1 on extra junk"),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(parse(b"esp status now"), Err(ParseError::TrailingInput));
+        assert_eq!(
+            parse(b"esp led1 on  "),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+    }
+
+    #[test]
+    fn test_led_with_space() {
+        assert_eq!(
+            parse(b"esp led 2 on"),
+            Ok(set_command(Led::Led2, LedState::ON))
+        );
+        assert_eq!(parse(b"esp led 9 on"), Err(ParseError::UnknownLed));
+        // Only the full form takes a space, the short form is split by it.
+        assert_eq!(parse(b"esp l 2 on"), Err(ParseError::SplitToken));
+    }
+
+    #[test]
+    fn test_opcode() {
+        const LEDS: [Led; 4] = [Led::Led1, Led::Led2, Led::Led3, Led::Led4];
+        const STATES: [LedState; 2] = [LedState::ON, LedState::Off];
+        let mut opcodes = [0u16; 8];
+        for (i, led) in LEDS.iter().enumerate() {
+            for (j, state) in STATES.iter().enumerate() {
+                opcodes[i * 2 + j] = set_command(*led, *state).opcode();
+            }
+        }
+        for (i, opcode) in opcodes.iter().enumerate() {
+            assert!(!opcodes[(i + 1)..].contains(opcode));
+        }
+        assert_eq!(set_command(Led::Led2, LedState::Off).opcode(), 0x0101);
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            set_command(Led::Led3, LedState::Blink { period_ms: 500 }).opcode(),
+            0x02FF
+        );
+        assert_eq!(parse(b"esp status").unwrap().opcode(), 0xFF01);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_diagnose() {
+        assert_eq!(
+            diagnose("esp led1 on"),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+        let diagnostic = diagnose("esp led1 wfea").unwrap_err();
+        assert!(diagnostic.contains("in state"));
+        assert!(diagnostic.contains("esp led1 wfea\n         ^"));
+        assert!(diagnose("esp led9 on").unwrap_err().contains("in led"));
+        assert!(diagnose("esp status now")
+            .unwrap_err()
+            .contains("in end of the command"));
+    }
+
+    /// The near misses the loose matching used to accept, see the `from_slice` functions.
+    #[cfg(feature = "strict")]
+    mod near_miss {
+        use super::*;
+
+        #[test]
+        fn test_near_miss_states() {
+            for input in [
+                "esp led1 oonn",
+                "esp led1 onn",
+                "esp led1 offasdf",
+                "esp led1 xon",
+                "esp led1 blink 5off",
+            ] {
+                assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+            }
+        }
+
+        #[test]
+        fn test_near_miss_leds() {
+            for input in ["esp led11 on", "esp led2asdf on", "esp l12 on"] {
+                assert_eq!(parse_str(input), Err(ParseError::UnknownLed), "{input}");
+            }
+            for input in ["esp lled11 on", "esp asled2df on", "esp xl1 on"] {
+                assert_eq!(parse_str(input), Err(ParseError::UnknownCommand), "{input}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_safe_default() {
+        const BAD_COMMAND: &str = "esp led3 wfea";
+        let command = parse_uart(BAD_COMMAND.as_ptr(), BAD_COMMAND.len());
+        assert_eq!(command, Command::safe_default());
+        assert_eq!(
+            Command::from_framed(b"esp led3 on"),
+            Command::safe_default()
+        );
+        assert!(!command.success);
+        assert_eq!(command.kind, CommandKind::Clear);
+        assert_eq!(command.to_bytes(&mut [0u8; 16]), None);
+        // A failure does not look like turning off Led1.
+        assert_ne!(command, set_command(Led::Led1, LedState::Off));
+    }
+
+    #[test]
+    fn test_sequence_tag() {
+        let mut buf = [0u8; 24];
+        let command = parse(b"esp #42 led1 on").unwrap();
+        assert_eq!(
+            command,
+            Command {
+                seq: 42,
+                ..set_command(Led::Led1, LedState::ON)
+            }
+        );
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp #42 led1 on");
+        assert_eq!(parse(b"esp led1 on").unwrap().seq, 0);
+        assert_eq!(
+            parse(b"esp #70000 led1 on"),
+            Err(ParseError::InvalidSequence)
+        );
+        assert_eq!(parse(b"esp # led1 on"), Err(ParseError::InvalidSequence));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut buf = [0u8; 16];
+        let command = parse(b"esp clear").unwrap();
+        assert!(command.success);
+        assert_eq!(command.kind, CommandKind::Clear);
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp clear");
+        assert_eq!(parse(b"esp clea"), Err(ParseError::UnknownCommand));
+        assert_eq!(
+            parse(b"esp clear\t").map(|command| command.kind),
+            Ok(CommandKind::Clear)
+        );
+        assert_eq!(
+            parse(b"esp clear\r").map(|command| command.kind),
+            Ok(CommandKind::Clear)
+        );
+        assert_eq!(parse(b"esp clear led1"), Err(ParseError::TrailingInput));
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_from_slices() {
+        const COMMANDS: [&[u8]; 3] = [b"esp led1 on", b"esp 2x led 3 blink 2s", b"esp status"];
+        for command in COMMANDS {
+            let expected = parse(command).unwrap();
+            for split in 0..=command.len() {
+                let (first, second) = command.split_at(split);
+                assert_eq!(Command::from_slices(first, second), expected, "{split}");
+            }
+        }
+        assert!(!Command::from_slices(b"esp le", b"d5 on").success);
+    }
+
+    #[test]
+    fn test_from_slices_every_split() {
+        // The result is the one of the joined command, wherever the ring buffer wrapped.
+        const COMMANDS: [&[u8]; 10] = [
+            b"ESP@2 3x !hi #42 @1000 ack led2 hold on for 2s",
+            b"esp l4 toggle\r",
+            b"esp mask 5",
+            b"esp def front led1 led2",
+            b"esp cycle 200ms",
+            b"esp whoami",
+            b"esp",
+            b"esp led1 on off",
+            b"esp led1 o\x08on",
+            b"esp led1 on x",
+        ];
+        for command in COMMANDS {
+            let expected = parse(command).unwrap_or_default();
+            for split in 0..=command.len() {
+                let (first, second) = command.split_at(split);
+                assert_eq!(
+                    Command::from_slices(first, second),
+                    expected,
+                    "{command:?} {split}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_slices_too_long() {
+        let first = [b'x'; MAX_FRAME_LEN];
+        assert_eq!(
+            Command::from_slices(&first, b"esp led1 on"),
+            Command::safe_default()
+        );
+    }
+
+    #[test]
+    fn test_led_index() {
+        assert_eq!(LedIndex::new(0), None);
+        assert_eq!(LedIndex::new(MAX_LED + 1), None);
+        for index in 1..=MAX_LED {
+            let led = Led::from_index(LedIndex::new(index).unwrap());
+            assert_eq!(led.index().get(), index);
+        }
+        assert_eq!(Led::from_index(LedIndex::new(3).unwrap()), Led::Led3);
+        assert_eq!(Led::Led1.index(), LedIndex::new(1).unwrap());
+    }
+
+    #[test]
+    fn test_parse_into() {
+        let mut command = Command::safe_default();
+        #[cfg(feature = "extended-states")]
+        assert!(command.parse_into(b"esp 3x !hi #7 led2 blink 1s"));
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            command,
+            Command {
+                repeat: 3,
+                priority: PRIORITY_HIGH,
+                seq: 7,
+                ..set_command(Led::Led2, LedState::Blink { period_ms: 1000 })
+            }
+        );
+        assert!(command.parse_into(b"esp led4 off"));
+        assert_eq!(command, set_command(Led::Led4, LedState::Off));
+        assert!(command.parse_into(b"esp #9 led1 on"));
+        assert!(!command.parse_into(b"esp led1 wfea"));
+        assert_eq!(command, Command::safe_default());
+    }
+
+    #[test]
+    fn test_identify() {
+        let mut buf = [0u8; 16];
+        let command = parse(b"esp whoami").unwrap();
+        assert_eq!(command.kind, CommandKind::Identify);
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp whoami");
+        assert_eq!(parse(b"esp whoam"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(
+            Command::from_hex(b"657370206c656431206f6e"),
+            set_command(Led::Led1, LedState::ON)
+        );
+        assert_eq!(
+            Command::from_hex(b"657370206C656434206F6666"),
+            set_command(Led::Led4, LedState::Off)
+        );
+        assert_eq!(
+            Command::from_hex(b"657370206c656431206f6"),
+            Command::safe_default()
+        );
+        assert_eq!(
+            Command::from_hex(b"657370206c656431206fzz"),
+            Command::safe_default()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_rgb_state() {
+        let rgb = set_command(
+            Led::Led2,
+            LedState::Rgb {
+                r: 255,
+                g: 128,
+                b: 0,
+            },
+        );
+        assert_eq!(parse(b"esp led2 rgb 255 128 0"), Ok(rgb));
+        let mut buf = [0u8; 32];
+        let length = rgb.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led2 rgb 255 128 0");
+        assert_eq!(rgb.opcode(), 0x01FF);
+        for input in [
+            "esp led2 rgb 1 2",
+            "esp led2 rgb 1 2 256",
+            "esp led2 rgbx 1 2 3",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_max_args() {
+        let mut input = [0u8; 512];
+        input[..12].copy_from_slice(b"esp led1 rgb");
+        for pair in input[12..].chunks_exact_mut(2) {
+            pair.copy_from_slice(b" 1");
+        }
+        assert_eq!(parse(&input), Err(ParseError::FrameTooLong));
+        let config = BoardConfig {
+            max_frame: input.len(),
+            ..BoardConfig::DEFAULT
+        };
+        assert_eq!(
+            parse_with_config(&input, &config),
+            Err(ParseError::UnknownState)
+        );
+        // Parsing gives up at the first argument over the limit.
+        assert!(arguments(&input[12..]).is_err());
+        let (rest, (_, count)) = arguments(&b" 1 2 3 4 x"[..]).unwrap();
+        assert_eq!((rest, count), (&b" x"[..], MAX_ARGS));
+        assert!(arguments(&b" 1 2 3 4 5"[..]).is_err());
+    }
+
+    #[test]
+    fn test_coalesce_duplicates() {
+        let mut cmds = [Command::safe_default(); 5];
+        for (command, input) in cmds.iter_mut().zip(CommandStream::new(
+            b"esp led1 on; esp led2 on; esp status; esp led1 off; esp led2 off",
+        )) {
+            *command = input;
+        }
+        let length = Command::coalesce(&mut cmds);
+        assert_eq!(length, 3);
+        assert_eq!(cmds[0].kind, CommandKind::Status);
+        assert_eq!(cmds[1], set_command(Led::Led1, LedState::Off));
+        assert_eq!(cmds[2], set_command(Led::Led2, LedState::Off));
+    }
+
+    #[test]
+    fn test_coalesce_distinct() {
+        let mut cmds = [
+            set_command(Led::Led1, LedState::ON),
+            set_command(Led::Led2, LedState::Off),
+            set_command(Led::Led3, LedState::ON),
+            Command::safe_default(),
+        ];
+        let expected = cmds;
+        assert_eq!(Command::coalesce(&mut cmds), 4);
+        assert_eq!(cmds, expected);
+    }
+
+    #[test]
+    fn test_state_synonyms() {
+        for (input, state) in [
+            ("esp led1 enable", LedState::ON),
+            ("esp led1 high", LedState::ON),
+            ("esp led1 disable", LedState::Off),
+            ("esp led1 low", LedState::Off),
+        ] {
+            assert_eq!(
+                parse_str(input),
+                Ok(set_command(Led::Led1, state)),
+                "{input}"
+            );
+        }
+        for input in ["esp led1 enabled", "esp led1 hi", "esp led1 lowx"] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_with_config() {
+        let small = BoardConfig {
+            leds: 2,
+            max_frame: 16,
+            ascii_only: true,
+            prefix: b"esp ",
+        };
+        assert_eq!(
+            parse_with_config(b"esp led2 on", &small),
+            Ok(set_command(Led::Led2, LedState::ON))
+        );
+        assert_eq!(
+            parse_with_config(b"esp led3 on", &small),
+            Err(ParseError::UnknownLed)
+        );
+        assert_eq!(
+            parse_with_config(b"esp led3 on", &BoardConfig::default()),
+            Ok(set_command(Led::Led3, LedState::ON))
+        );
+        assert_eq!(
+            parse_with_config(b"esp 2x led1 blink 500ms", &small),
+            Err(ParseError::FrameTooLong)
+        );
+        #[cfg(feature = "extended-states")]
+        assert!(parse(b"esp 2x led1 blink 500ms").is_ok());
+    }
+
+    #[test]
+    fn test_has_complete() {
+        assert!(Command::has_complete(b"esp led1 on\n"));
+        assert!(Command::has_complete(b"esp led1 on\nesp le"));
+        assert!(!Command::has_complete(b"esp led1 on"));
+        assert!(!Command::has_complete(b""));
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_fade_out() {
+        let fade_out = set_command(Led::Led1, LedState::FadeOut { duration_ms: 1000 });
+        assert_eq!(parse(b"esp led1 fadeout 1000"), Ok(fade_out));
+        let mut buf = [0u8; 32];
+        let length = fade_out.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 fadeout 1000");
+        for input in [
+            "esp led1 fadeout 70000",
+            "esp led1 fadeout",
+            "esp led1 fadeout ",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_parse_stats() {
+        // The counters are shared with the other tests, which may run at the same time.
+        let (ok, fail, total) = parse_stats();
+        assert!(Command::from_slice(b"esp led1 on").success);
+        assert!(Command::from_slice(b"esp led2 on").success);
+        assert!(!Command::from_slice(b"esp led9 on").success);
+        let (ok_after, fail_after, total_after) = parse_stats();
+        assert!(ok_after - ok >= 2);
+        assert!(fail_after - fail >= 1);
+        assert!(total_after - total >= 3);
+    }
+
+    #[test]
+    fn test_ascii_only() {
+        assert_eq!(parse(b"esp led1 \xc3n"), Err(ParseError::NonAscii));
+        assert_eq!(
+            parse(b"esp led1 on"),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+        let binary = BoardConfig {
+            ascii_only: false,
+            ..BoardConfig::DEFAULT
+        };
+        assert_eq!(
+            parse_with_config(b"esp led1 \xc3n", &binary),
+            Err(ParseError::UnknownState)
+        );
+        assert!(!Command::from_slice(b"esp led1 on \x80").success);
+        // A trailing checksum byte is left to the caller.
+        assert_eq!(
+            parse_lenient(b"esp led1 on \x80"),
+            Ok((set_command(Led::Led1, LedState::ON), true))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_parsed_command_from_ffi() {
+        for parsed in [
+            ParsedCommand::Set {
+                led: Led::Led3,
+                state: LedState::Blink { period_ms: 250 },
+            },
+            ParsedCommand::Status,
+            ParsedCommand::Clear,
+            ParsedCommand::Identify,
+            ParsedCommand::Noop,
+            ParsedCommand::Mask { bits: 9 },
+            ParsedCommand::InvertAll,
+            ParsedCommand::DefineGroup {
+                leds: [Led::Led2, Led::Led4, Led::Led1, Led::Led1],
+                name: *b"rear\0\0\0\0",
+                count: 2,
+                name_len: 4,
+            },
+            ParsedCommand::Fault { code: 3 },
+            ParsedCommand::Help,
+            ParsedCommand::Cycle { step_ms: 200 },
+        ] {
+            let command = parsed.to_ffi();
+            assert!(command.success);
+            assert_eq!(ParsedCommand::from_ffi(&command), Some(parsed));
+        }
+        assert_eq!(
+            ParsedCommand::from_ffi(&parse(b"esp 2x led1 on").unwrap()),
+            Some(ParsedCommand::Set {
+                led: Led::Led1,
+                state: LedState::ON
+            })
+        );
+        assert_eq!(ParsedCommand::from_ffi(&Command::safe_default()), None);
+    }
+
+    #[test]
+    fn test_recording_sink() {
+        let mut sink = RecordingSink::<3>::new();
+        for frame in [
+            &b"esp led1 on"[..],
+            b"esp led9 on",
+            b"esp status",
+            b"esp led2 off",
+        ] {
+            sink.feed(frame);
+        }
+        assert_eq!(
+            sink.commands(),
+            [
+                set_command(Led::Led1, LedState::ON),
+                Command::safe_default(),
+                parse(b"esp status").unwrap(),
+            ]
+        );
+        assert!(!sink.record(Command::safe_default()));
+    }
+
+    #[test]
+    fn test_noop() {
+        for input in ["esp noop", "esp ping", "esp #7 ping"] {
+            let command = parse_str(input).unwrap();
+            assert_eq!(command.kind, CommandKind::Noop, "{input}");
+        }
+        let mut buf = [0u8; 16];
+        let length = parse(b"esp ping").unwrap().to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp noop");
+        assert_eq!(parse(b"esp pings"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp noop x"), Err(ParseError::TrailingInput));
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_canonical_len() {
+        for input in [
+            "esp led1 on",
+            "esp led4 off",
+            "esp 12x !lo #300 led2 blink 2s",
+            "esp led3 rgb 255 0 17",
+            "esp #9 status",
+        ] {
+            let command = parse_str(input).unwrap();
+            let mut buf = [0u8; MAX_FRAME_LEN];
+            let length = command.to_bytes(&mut buf).unwrap();
+            assert_eq!(command.canonical_len(), length, "{input}");
+        }
+        assert_eq!(parse(b"esp led1 blink 2s").unwrap().canonical_len(), 19);
+        assert_eq!(Command::safe_default().canonical_len(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_ramp() {
+        let ramp = set_command(
+            Led::Led1,
+            LedState::Ramp {
+                values: [0, 64, 128, 255],
+                count: 4,
+            },
+        );
+        assert_eq!(parse(b"esp led1 ramp 0 64 128 255"), Ok(ramp));
+        let mut buf = [0u8; 32];
+        let length = ramp.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 ramp 0 64 128 255");
+        assert_eq!(
+            parse(b"esp led1 ramp 7").unwrap().state,
+            LedState::Ramp {
+                values: [7, 0, 0, 0],
+                count: 1
+            }
+        );
+        // Over capacity and out of range values fail, nothing is truncated.
+        for input in [
+            "esp led1 ramp 0 64 128 255 255",
+            "esp led1 ramp 0 256",
+            "esp led1 ramp 0 300",
+            "esp led1 ramp",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        for input in [
+            "esp led1 on",
+            "esp 3x led2 blink 1s",
+            "esp status",
+            "esp led1",
+            "esp led5 on",
+            "esp led1 on x",
+            "led1 on",
+        ] {
+            assert_eq!(
+                Command::validate(input.as_bytes()),
+                parse_str(input).map(|_| ()),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_color_names() {
+        let rgb = |r, g, b| Ok(set_command(Led::Led1, LedState::Rgb { r, g, b }));
+        assert_eq!(parse(b"esp led1 color red"), rgb(255, 0, 0));
+        assert_eq!(parse(b"esp led1 color white"), rgb(255, 255, 255));
+        for input in [
+            "esp led1 color chartreuse",
+            "esp led1 color redx",
+            "esp led1 color",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let input = b"# lights\nesp led1 on;; esp led2 off \n\n\tesp status;";
+        let mut segments = Command::segments(input);
+        assert_eq!(segments.next(), Some(&b"esp led1 on"[..]));
+        assert_eq!(segments.next(), Some(&b"esp led2 off"[..]));
+        let last = segments.next().unwrap();
+        assert_eq!(last, b"esp status");
+        // Zero-copy, the segment points into the input.
+        assert_eq!(last.as_ptr(), input[(input.len() - 11)..].as_ptr());
+        assert_eq!(segments.next(), None);
+    }
+
+    #[test]
+    fn test_on_brightness() {
+        assert_eq!(
+            parse(b"esp led1 on"),
+            Ok(set_command(
+                Led::Led1,
+                LedState::On {
+                    brightness: BRIGHTNESS_FULL
+                }
+            ))
+        );
+        let dimmed = set_command(Led::Led1, LedState::On { brightness: 128 });
+        assert_eq!(parse(b"esp led1 on 128"), Ok(dimmed));
+        assert_eq!(parse(b"esp led1 high 128"), Ok(dimmed));
+        let mut buf = [0u8; 32];
+        let length = dimmed.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 on 128");
+        assert_eq!(dimmed.opcode(), 0x00FF);
+        #[cfg(feature = "strict")]
+        for input in ["esp led1 on 256", "esp led1 on 1 2", "esp led1 on128"] {
+            assert!(parse_str(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        assert_eq!(parse(b"esp wibble"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp !hi wibble on"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp led9 on"), Err(ParseError::UnknownLed));
+        assert_eq!(parse(b"esp LED9 on"), Err(ParseError::UnknownLed));
+        // Only "led" or "l" followed by a digit is taken for a led.
+        assert_eq!(parse(b"esp ledx on"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp list"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp lights on"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        /// Fixed buffer to render the errors into, like on the target.
+        struct Buffer {
+            bytes: [u8; 48],
+            length: usize,
+        }
+
+        impl Write for Buffer {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let end = self.length + s.len();
+                let slot = self.bytes.get_mut(self.length..end).ok_or(fmt::Error)?;
+                slot.copy_from_slice(s.as_bytes());
+                self.length = end;
+                Ok(())
+            }
+        }
+
+        for (input, rendered) in [
+            ("esp led1 wfea", "unknown state at byte 9"),
+            ("esp led1", "missing state at byte 8"),
+            ("esp led1 on  x", "trailing input at byte 13"),
+            ("esp 3x !mid led1 on", "invalid priority at byte 7"),
+            ("sp led1 on", "missing keyword \"esp \" at byte 0"),
+        ] {
+            let error = parse_positioned(input.as_bytes()).unwrap_err();
+            let mut buffer = Buffer {
+                bytes: [0; 48],
+                length: 0,
+            };
+            error.write_to(&mut buffer).unwrap();
+            assert_eq!(&buffer.bytes[..buffer.length], rendered.as_bytes());
+        }
+        assert_eq!(
+            parse_positioned(b"esp led1 \xc3n").unwrap_err(),
+            ParseErrorAt {
+                error: ParseError::NonAscii,
+                offset: 9
+            }
+        );
+        assert!(parse_positioned(b"esp led1 on").is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_expand_aliases() {
+        const ALIASES: &[(&[u8], &[u8])] =
+            &[(b"panic", b"esp all blink 100"), (b"dark", b"esp clear")];
+        let mut buf = [0u8; 32];
+        let length = expand_aliases(b"panic", ALIASES, &mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp all blink 100");
+        let all: &[Led] = &[Led::Led1, Led::Led2, Led::Led3, Led::Led4];
+        let mut out = [Command::safe_default(); 4];
+        assert_eq!(parse_group(&buf[..length], &[(b"all", all)], &mut out), 4);
+        let length = expand_aliases(b"dark \r", ALIASES, &mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp clear \r");
+        // Unknown tokens and aliases that are only a prefix are passed through.
+        for input in [&b"esp led1 on"[..], b"darkness"] {
+            let length = expand_aliases(input, ALIASES, &mut buf).unwrap();
+            assert_eq!(&buf[..length], input);
+        }
+        assert_eq!(expand_aliases(b"panic", ALIASES, &mut [0u8; 8]), None);
+    }
+
+    #[test]
+    fn test_parse_no_prefix() {
+        assert_eq!(
+            parse_no_prefix(b"led1 on"),
+            set_command(Led::Led1, LedState::ON)
+        );
+        assert_eq!(parse_no_prefix(b"2x status").kind, CommandKind::Status);
+        assert_eq!(parse(b"led1 on"), Err(ParseError::MissingPrefix));
+        assert_eq!(parse_no_prefix(b"esp led1 on"), Command::safe_default());
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_encode_multi() {
+        let cmds = [
+            set_command(Led::Led1, LedState::ON),
+            parse(b"esp 2x !hi #5 led3 blink 250").unwrap(),
+            parse(b"esp status").unwrap(),
+            set_command(Led::Led2, LedState::Rgb { r: 1, g: 2, b: 3 }),
+        ];
+        let mut buf = [0u8; 128];
+        let length = encode_multi(&cmds, &mut buf).unwrap();
+        assert_eq!(&buf[..11], b"esp led1 on");
+        let mut parsed = [Err(ParseError::MissingPrefix); 8];
+        assert_eq!(parse_multi(&buf[..length], &mut parsed), cmds.len());
+        for (parsed, command) in parsed.iter().zip(cmds.iter()) {
+            assert_eq!(parsed, &Ok(*command));
+        }
+        assert_eq!(encode_multi(&cmds, &mut buf[..length - 1]), None);
+        assert_eq!(encode_multi(&[Command::safe_default()], &mut buf), None);
+        assert_eq!(encode_multi(&[], &mut buf), Some(0));
+    }
+
+    #[test]
+    fn test_parse_relative() {
+        assert_eq!(
+            parse_relative(b"esp next on", Led::Led2),
+            set_command(Led::Led3, LedState::ON)
+        );
+        assert_eq!(
+            parse_relative(b"esp prev on", Led::Led1),
+            set_command(Led::Led4, LedState::ON)
+        );
+        assert_eq!(Led::Led4.next(), Led::Led1);
+        assert_eq!(
+            parse_relative(b"esp led2 off", Led::Led1),
+            set_command(Led::Led2, LedState::Off)
+        );
+        assert_eq!(parse(b"esp next on"), Err(ParseError::UnknownCommand));
+        assert!(!parse_relative(b"esp nextx on", Led::Led1).success);
+    }
+
+    #[test]
+    fn test_command_default() {
+        assert!(!Command::default().success);
+        assert_eq!(Command::default(), Command::safe_default());
+        assert_eq!(Command::from_slice(b"esp led1 wfea"), Command::default());
+    }
+
+    #[test]
+    fn test_multiple_states() {
+        assert_eq!(parse(b"esp led1 on off"), Err(ParseError::MultipleStates));
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            parse(b"esp led1 blink 1s rgb 1 2 3"),
+            Err(ParseError::MultipleStates)
+        );
+        assert_eq!(
+            parse_lenient(b"esp led1 off on"),
+            Err(ParseError::MultipleStates)
+        );
+        assert_eq!(
+            parse(b"esp led1 on 12"),
+            Ok(set_command(Led::Led1, LedState::On { brightness: 12 }))
+        );
+        assert_eq!(parse(b"esp led1 on x"), Err(ParseError::TrailingInput));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_std_error() {
+        use std::boxed::Box;
+        use std::error::Error;
+        use std::string::ToString;
+
+        fn host_tool(input: &[u8]) -> Result<Command, Box<dyn Error>> {
+            let command = parse(input)?;
+            Ok(command)
+        }
+
+        fn positioned(input: &[u8]) -> Result<Command, ParseError> {
+            Ok(parse_positioned(input)?)
+        }
+
+        assert!(host_tool(b"esp led1 on").is_ok());
+        let error = host_tool(b"esp led1 wfea").unwrap_err();
+        assert_eq!(error.to_string(), "unknown state");
+        assert_eq!(
+            error.downcast_ref::<ParseError>(),
+            Some(&ParseError::UnknownState)
+        );
+        assert_eq!(positioned(b"esp led9 on"), Err(ParseError::UnknownLed));
+        assert_eq!(
+            parse_positioned(b"esp led1").unwrap_err().to_string(),
+            "missing state at byte 8"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_pattern() {
+        let pattern = set_command(
+            Led::Led1,
+            LedState::Pattern {
+                bits: 0b10110,
+                len: 5,
+            },
+        );
+        assert_eq!(parse(b"esp led1 pattern 10110"), Ok(pattern));
+        let mut buf = [0u8; 48];
+        let length = pattern.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 pattern 10110");
+        let command = parse(b"esp led1 pattern 0001000000000001").unwrap();
+        assert_eq!(
+            command.state,
+            LedState::Pattern {
+                bits: 0x1001,
+                len: 16
+            }
+        );
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 pattern 0001000000000001");
+        for input in [
+            "esp led1 pattern 10101010101010101",
+            "esp led1 pattern 1021",
+            "esp led1 pattern ",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        for (input, kind) in [
+            ("esp led1 on", Some(CommandKind::Set)),
+            ("esp led1 wfea", Some(CommandKind::Set)),
+            ("esp l3", Some(CommandKind::Set)),
+            ("esp status now", Some(CommandKind::Status)),
+            ("esp 0x !mid clear", Some(CommandKind::Clear)),
+            ("esp #99999 ping", Some(CommandKind::Noop)),
+            ("esp wibble", None),
+            ("esp led9 on", None),
+            ("esp 3x", None),
+            ("led1 on", None),
+        ] {
+            assert_eq!(Command::classify(input.as_bytes()), kind, "{input}");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_duration_unit_space() {
+        let blink = |period_ms| Ok(set_command(Led::Led1, LedState::Blink { period_ms }));
+        assert_eq!(parse(b"esp led1 blink 500 ms"), blink(500));
+        assert_eq!(parse(b"esp led1 blink 500ms"), blink(500));
+        assert_eq!(parse(b"esp led1 blink 2 s"), blink(2000));
+        assert_eq!(parse(b"esp led1 blink 500"), blink(500));
+        assert_eq!(
+            parse(b"esp led1 blink 500 xs"),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(
+            parse(b"esp led1 blink 500 msx"),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(parse(b"esp led1 blink 70 s"), Err(ParseError::UnknownState));
+    }
+
+    #[test]
+    fn test_parse_buf() {
+        let mut buf = [0u8; 16];
+        buf[..11].copy_from_slice(b"esp led1 on");
+        assert_eq!(parse_buf(&buf, 11), set_command(Led::Led1, LedState::ON));
+        assert_eq!(parse_buf(&buf, 8), Command::safe_default());
+        assert_eq!(parse_buf(&buf, 99), Command::safe_default());
+        let full = *b"esp led2 off";
+        assert_eq!(
+            parse_buf(&full, usize::MAX),
+            set_command(Led::Led2, LedState::Off)
+        );
+    }
+
+    #[test]
+    fn test_command_parser_composition() {
+        use nom::sequence::{pair, preceded};
+
+        // A frame carrying a command and its checksum, "esp led1 on *42".
+        let mut frame = pair(command_parser, preceded(tag(" *"), character::u8));
+        let (rest, (parsed, checksum)) = frame(&b"esp led1 on *42\n"[..]).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedCommand::Set {
+                led: Led::Led1,
+                state: LedState::ON
+            }
+        );
+        assert_eq!((checksum, rest), (42, &b"\n"[..]));
+        assert!(frame(&b"esp led9 on *42"[..]).is_err());
+
+        #[cfg(feature = "extended-states")]
+        let mut target = pair(Led::from_slice, preceded(tag(" "), LedState::from_slice));
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            target(&b"l3 blink 1s x"[..]),
+            Ok((&b" x"[..], (Led::Led3, LedState::Blink { period_ms: 1000 })))
+        );
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp led1,led2 on", &mut out), Ok(2));
+        assert_eq!(out[0], set_command(Led::Led1, LedState::ON));
+        assert_eq!(out[1], set_command(Led::Led2, LedState::ON));
+        assert_eq!(parse_list(b"esp !hi l4,l3,l2,l1 off", &mut out), Ok(4));
+        assert_eq!(out[3].priority, PRIORITY_HIGH);
+        assert_eq!(parse_list(b"esp led3 off", &mut out), Ok(1));
+
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(
+            parse_list(b"esp led1,led1 on", &mut out),
+            Err(ParseError::DuplicateTarget)
+        );
+        assert_eq!(
+            parse_list(b"esp l1,led2,l1 on", &mut out),
+            Err(ParseError::DuplicateTarget)
+        );
+        assert_eq!(
+            parse_list(b"esp led1,,led2 on", &mut out),
+            Err(ParseError::UnknownLed)
+        );
+        // Same error as for a single led, see `set_target`.
+        for input in [
+            "esp led1,led2 on off",
+            "esp all on off",
+            "esp led3 off toggle",
+        ] {
+            assert_eq!(
+                parse_list(input.as_bytes(), &mut out),
+                Err(ParseError::MultipleStates),
+                "{input}"
+            );
+        }
+        assert_eq!(out, [Command::safe_default(); MAX_LED as usize]);
+    }
+
+    #[test]
+    fn test_timestamp_tag() {
+        let command = parse(b"esp @1000 led1 on").unwrap();
+        assert_eq!(command.at_tick, 1000);
+        assert_eq!(command.led, Led::Led1);
+        assert_eq!(parse(b"esp led1 on").unwrap().at_tick, 0);
+        let command = parse(b"esp #3 @4000000000 !hi status").unwrap();
+        assert_eq!(command.at_tick, 4_000_000_000);
+        let mut buf = [0u8; 48];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp !hi #3 @4000000000 status");
+        for input in ["esp @ led1 on", "esp @5000000000 led1 on", "esp @10led1 on"] {
+            assert_eq!(
+                parse_str(input),
+                Err(ParseError::InvalidTimestamp),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_strip_bom() {
+        let led1_on = Ok(set_command(Led::Led1, LedState::ON));
+        assert_eq!(parse(b"\xEF\xBB\xBFesp led1 on"), Err(ParseError::NonAscii));
+        assert_eq!(parse(strip_bom(b"\xEF\xBB\xBFesp led1 on")), led1_on);
+        assert_eq!(parse(strip_bom(b"\xBB\xBFesp led1 on")), led1_on);
+        assert_eq!(parse(strip_bom(b"\xBFesp led1 on")), led1_on);
+        assert_eq!(parse(strip_bom(b"esp led1 on")), led1_on);
+        // Only a single leading mark is removed.
+        assert_eq!(strip_bom(b"\xEF\xBB\xBF\xEF\xBB\xBF"), b"\xEF\xBB\xBF");
+        assert_eq!(strip_bom(b"\xEFesp"), b"\xEFesp");
+    }
+
+    #[test]
+    fn test_default_command_static() {
+        static LAST_COMMAND: Command = DEFAULT_COMMAND;
+        const LEDS_OFF: [Command; 2] = [DEFAULT_COMMAND; 2];
+        assert_eq!(LAST_COMMAND, Command::default());
+        assert!(!LAST_COMMAND.success);
+        assert_eq!(LEDS_OFF[1].kind, CommandKind::Clear);
+    }
+
+    #[test]
+    fn test_mask() {
+        let command = parse(b"esp mask 5").unwrap();
+        assert_eq!(command.kind, CommandKind::Mask { bits: 5 });
+        let mut states = [LedState::Off; MAX_LED as usize];
+        command.apply(&mut states);
+        assert_eq!(
+            states,
+            [LedState::ON, LedState::Off, LedState::ON, LedState::Off]
+        );
+        parse(b"esp mask 0").unwrap().apply(&mut states);
+        assert_eq!(states, [LedState::Off; MAX_LED as usize]);
+        let mut buf = [0u8; 16];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp mask 5");
+        assert_eq!(command.opcode(), 0xFF05);
+        assert_eq!(
+            parse(b"esp mask 15").unwrap().kind,
+            CommandKind::Mask { bits: 15 }
+        );
+        for input in ["esp mask 16", "esp mask", "esp mask 256", "esp mask x"] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidMask), "{input}");
+        }
+        let two_leds = BoardConfig {
+            leds: 2,
+            ..BoardConfig::DEFAULT
+        };
+        assert_eq!(
+            parse_with_config(b"esp mask 4", &two_leds),
+            Err(ParseError::InvalidMask)
+        );
+        for leds in [32, u8::MAX] {
+            let config = BoardConfig {
+                leds,
+                ..BoardConfig::DEFAULT
+            };
+            assert_eq!(
+                parse_with_config(b"esp mask 15", &config).map(|command| command.kind),
+                Ok(CommandKind::Mask { bits: 15 })
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_apply() {
+        let mut states = [LedState::Off; MAX_LED as usize];
+        parse(b"esp led2 blink 1s").unwrap().apply(&mut states);
+        parse(b"esp status").unwrap().apply(&mut states);
+        assert_eq!(states[1], LedState::Blink { period_ms: 1000 });
+        Command::safe_default().apply(&mut states);
+        assert_eq!(states, [LedState::Off; MAX_LED as usize]);
+    }
+
+    #[test]
+    fn test_parse_multi_bad_segment() {
+        let mut out = [Ok(Command::safe_default()); 4];
+        let count = parse_multi(b"esp led1 on; esp led1 wfea\nesp led3 off", &mut out);
+        assert_eq!(count, 3);
+        assert_eq!(out[0], Ok(set_command(Led::Led1, LedState::ON)));
+        assert_eq!(out[1], Err(ParseError::UnknownState));
+        assert_eq!(out[2], Ok(set_command(Led::Led3, LedState::Off)));
+        let mut small = [Ok(Command::safe_default()); 1];
+        assert_eq!(parse_multi(b"esp x; esp led1 on", &mut small), 1);
+        assert_eq!(small[0], Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_invert_all() {
+        let command = parse(b"esp invert").unwrap();
+        assert_eq!(command.kind, CommandKind::InvertAll);
+        let mut states = [
+            LedState::ON,
+            LedState::Off,
+            LedState::Blink { period_ms: 100 },
+            LedState::Off,
+        ];
+        command.apply(&mut states);
+        assert_eq!(
+            states,
+            [LedState::Off, LedState::ON, LedState::Off, LedState::ON]
+        );
+        let mut buf = [0u8; 16];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp invert");
+        assert_eq!(parse(b"esp inverted"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_keywords_ignore_case() {
+        for input in ["ESP LED1 ON", "Esp Led1 On", "esp LED 1 on", "eSp led1 oN"] {
+            assert_eq!(
+                parse_str(input),
+                Ok(set_command(Led::Led1, LedState::ON)),
+                "{input}"
+            );
+        }
+        assert_eq!(
+            parse_str("ESP LED2 OFF"),
+            Ok(set_command(Led::Led2, LedState::Off))
+        );
+        assert_eq!(Command::classify(b"ESP led3 on"), Some(CommandKind::Set));
+        // Values and the other keywords keep their case.
+        for input in ["esp led1 color RED", "esp led1 HIGH", "esp led1 Blink 1s"] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+        assert_eq!(parse_str("esp STATUS"), Err(ParseError::UnknownCommand));
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            parse_str("esp led1 color red"),
+            Ok(set_command(Led::Led1, LedState::Rgb { r: 255, g: 0, b: 0 }))
+        );
+    }
+
+    #[test]
+    fn test_checksum_bytes() {
+        let mut buf = [0u8; 32];
+        let command = parse(b"esp led1 on").unwrap();
+        let length = command.checksum_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 on*3B");
+        assert_eq!(parse_checksummed(&buf[..length]), Ok(command));
+        assert_eq!(parse_checksummed(b"esp led1 on*3b\r"), Ok(command));
+        for command in [
+            CommandBuilder::new()
+                .led(Led::Led3)
+                .state(LedState::Off)
+                .repeat(3)
+                .build(),
+            parse(b"esp #7 mask 5").unwrap(),
+            parse(b"esp status").unwrap(),
+        ] {
+            let length = command.checksum_bytes(&mut buf).unwrap();
+            assert_eq!(parse_checksummed(&buf[..length]), Ok(command));
+        }
+        // The checksum does not fit.
+        assert_eq!(command.checksum_bytes(&mut buf[..12]), None);
+        assert_eq!(Command::safe_default().checksum_bytes(&mut buf), None);
+        for input in [
+            "esp led1 on*00",
+            "esp led1 on",
+            "esp led1 on*3",
+            "esp led1 on*3BB",
+        ] {
+            assert_eq!(
+                parse_checksummed(input.as_bytes()),
+                Err(ParseError::InvalidChecksum),
+                "{input}"
+            );
+        }
+        // A valid checksum over an invalid command.
+        assert_eq!(
+            parse_checksummed(b"esp led1*1A"),
+            Err(ParseError::MissingState)
+        );
+    }
+
+    #[test]
+    fn test_define_group() {
+        let command = parse(b"esp def front led1 led2").unwrap();
+        let group = command.kind.group();
+        assert_eq!(group, Some((&b"front"[..], &[Led::Led1, Led::Led2][..])));
+        assert_eq!(
+            Command::classify(b"esp def front led1").map(|kind| kind.code()),
+            Some(7)
+        );
+        let mut buf = [0u8; 32];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp def front led1 led2");
+        // The firmware expands the stored group.
+        let (name, leds) = group.unwrap();
+        let mut out = [Command::safe_default(); 2];
+        assert_eq!(parse_group(b"esp front on", &[(name, leds)], &mut out), 2);
+        assert_eq!(out[1], set_command(Led::Led2, LedState::ON));
+        assert_eq!(
+            parse(b"esp def all led1 led2 led3 led4 led1"),
+            Err(ParseError::InvalidGroup)
+        );
+        for input in [
+            "esp def front",
+            "esp def",
+            "esp def led1 led2",
+            "esp def frontleds led1",
+            "esp def fr-nt led1",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidGroup), "{input}");
+        }
+        assert_eq!(
+            parse(b"esp def front led2 led2"),
+            Err(ParseError::DuplicateTarget)
+        );
+        let config = BoardConfig {
+            leds: 2,
+            ..BoardConfig::DEFAULT
+        };
+        assert_eq!(
+            parse_with_config(b"esp def rear led3", &config),
+            Err(ParseError::UnknownLed)
+        );
+    }
+
+    #[test]
+    fn test_parse_uart_capacity() {
+        let mut buf = [0xAAu8; 32];
+        buf[..12].copy_from_slice(b"esp led1 on\n");
+        assert_eq!(
+            parse_uart(buf.as_ptr(), buf.len()),
+            set_command(Led::Led1, LedState::ON)
+        );
+        let mut buf = [0u8; 32];
+        buf[..12].copy_from_slice(b"esp led2 off");
+        assert_eq!(
+            parse_uart(buf.as_ptr(), buf.len()),
+            set_command(Led::Led2, LedState::Off)
+        );
+        // The terminator is only looked for within the length.
+        assert!(!parse_uart(buf.as_ptr(), 10).success);
+        let frame = b"esp led3 off\nesp led9 on";
+        assert_eq!(
+            parse_uart(frame.as_ptr(), frame.len()),
+            set_command(Led::Led3, LedState::Off)
+        );
+    }
+
+    #[test]
+    fn test_all_commands() {
+        let mut out = [Command::safe_default(); 2 * MAX_LED as usize];
+        for led_count in 0..=MAX_LED {
+            let count = all_commands(led_count, &mut out);
+            assert_eq!(count, usize::from(led_count) * 2);
+            for command in &out[..count] {
+                let mut buf = [0u8; 16];
+                let length = command.to_bytes(&mut buf).unwrap();
+                assert_eq!(parse(&buf[..length]), Ok(*command));
+            }
+        }
+        assert_eq!(
+            out[..4],
+            [
+                set_command(Led::Led1, LedState::ON),
+                set_command(Led::Led1, LedState::Off),
+                set_command(Led::Led2, LedState::ON),
+                set_command(Led::Led2, LedState::Off),
+            ]
+        );
+        assert_eq!(all_commands(9, &mut out), 2 * MAX_LED as usize);
+        assert_eq!(all_commands(2, &mut out[..3]), 0);
+    }
+
+    #[test]
+    fn test_fault() {
+        let command = parse(b"esp fault 3").unwrap();
+        assert_eq!(command.kind, CommandKind::Fault { code: 3 });
+        assert_eq!(
+            parse(b"esp fault 255").map(|command| command.kind),
+            Ok(CommandKind::Fault { code: 255 })
+        );
+        let mut buf = [0u8; 16];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp fault 3");
+        let mut states = [LedState::ON; MAX_LED as usize];
+        command.apply(&mut states);
+        assert_eq!(states, [LedState::ON; MAX_LED as usize]);
+        for input in ["esp fault", "esp fault x", "esp fault 256", "esp fault 3x"] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidFault), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_token_tables() {
+        for (token, state) in STATE_TOKENS {
+            assert_eq!(LedState::from_slice(token), Ok((&b""[..], state)));
+        }
+        for (token, led) in LED_TOKENS {
+            assert_eq!(Led::from_slice(token), Ok((&b""[..], led)));
+            assert_eq!(led.token(), token);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_to_basic() {
+        for (input, state) in [
+            ("esp led1 on 128", LedState::ON),
+            ("esp led1 on", LedState::ON),
+            ("esp led1 blink 500ms", LedState::ON),
+            ("esp led1 pattern 101", LedState::ON),
+            ("esp led1 color red", LedState::ON),
+            ("esp led1 on 0", LedState::Off),
+            ("esp led1 off", LedState::Off),
+            ("esp led1 fadeout 1s", LedState::Off),
+            ("esp led1 rgb 0 0 0", LedState::Off),
+            ("esp led1 ramp 0 0", LedState::Off),
+        ] {
+            let command = parse_str(input).unwrap();
+            assert_eq!(
+                command.to_basic(),
+                Some(set_command(Led::Led1, state)),
+                "{input}"
+            );
+        }
+        let command = parse(b"esp 3x !hi led2 on 7").unwrap();
+        assert_eq!(
+            command.to_basic(),
+            Some(Command {
+                state: LedState::ON,
+                ..command
+            })
+        );
+        assert_eq!(parse(b"esp status").unwrap().to_basic(), None);
+        assert_eq!(Command::safe_default().to_basic(), None);
+    }
+
+    #[test]
+    fn test_hold_modifier() {
+        let command = parse(b"esp led1 hold on").unwrap();
+        assert_eq!(
+            (command.led, command.state, command.latched),
+            (Led::Led1, LedState::ON, false)
+        );
+        assert!(parse(b"esp led1 on").unwrap().latched);
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            parse(b"esp led2 hold blink 100").map(|command| command.latched),
+            Ok(false)
+        );
+        let mut buf = [0u8; 24];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 hold on");
+        assert_eq!(
+            CommandBuilder::new().latched(false).build(),
+            parse(b"esp led1 hold off").unwrap()
+        );
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp led1,led2 hold on", &mut out), Ok(2));
+        assert!(!out[1].latched);
+        #[cfg(feature = "strict")]
+        for input in ["esp led1 hold", "esp led1 hold hold on", "esp led1 holdon"] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_with_addr() {
+        let command = parse_with_addr(b"esp@2 led1 on", 2).unwrap();
+        assert_eq!(
+            command,
+            CommandBuilder::new().state(LedState::ON).addr(2).build()
+        );
+        assert_eq!(
+            parse_with_addr(b"esp@3 led1 on", 2),
+            Err(ParseError::NotAddressed)
+        );
+        // Broadcast, without address or with address 0.
+        for input in ["esp led1 on", "esp@0 led1 on"] {
+            assert_eq!(
+                parse_with_addr(input.as_bytes(), 2),
+                Ok(set_command(Led::Led1, LedState::ON)),
+                "{input}"
+            );
+        }
+        assert_eq!(
+            parse_with_addr(b"esp@2 status", 2).map(|command| command.kind),
+            Ok(CommandKind::Status)
+        );
+        for input in [
+            "esp@ led1 on",
+            "esp@x led1 on",
+            "esp@256 led1 on",
+            "esp@2led1 on",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidAddress), "{input}");
+        }
+        let mut buf = [0u8; 24];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp@2 led1 on");
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        // A valid command padded with trailing blanks up to the limit.
+        let mut frame = [b' '; MAX_FRAME_LEN + 1];
+        frame[..11].copy_from_slice(b"esp led1 on");
+        let command = set_command(Led::Led1, LedState::ON);
+        assert_eq!(parse(&frame[..MAX_FRAME_LEN]), Ok(command));
+        assert_eq!(Command::from_slice(&frame[..MAX_FRAME_LEN]), command);
+        // One byte over is rejected by the length guard, at the first byte over.
+        assert_eq!(parse(&frame), Err(ParseError::FrameTooLong));
+        assert_eq!(
+            parse_positioned(&frame),
+            Err(ParseErrorAt {
+                error: ParseError::FrameTooLong,
+                offset: MAX_FRAME_LEN
+            })
+        );
+        assert_eq!(Command::from_slice(&frame), Command::safe_default());
+        // The guard runs before any token is matched.
+        let garbage = [b'x'; MAX_FRAME_LEN + 1];
+        assert_eq!(parse(&garbage), Err(ParseError::FrameTooLong));
+        assert_eq!(
+            parse(&garbage[..MAX_FRAME_LEN]),
+            Err(ParseError::MissingPrefix)
+        );
+    }
+
+    #[test]
+    fn test_ack_modifier() {
+        let command = parse(b"esp ack led1 on").unwrap();
+        assert!(command.ack_requested);
+        assert_eq!((command.led, command.state), (Led::Led1, LedState::ON));
+        assert!(!parse(b"esp led1 on").unwrap().ack_requested);
+        assert_eq!(
+            parse(b"esp #5 ack status").map(|command| (command.seq, command.ack_requested)),
+            Ok((5, true))
+        );
+        assert_eq!(
+            Command::classify(b"esp ack led1 on"),
+            Some(CommandKind::Set)
+        );
+        let mut buf = [0u8; 24];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp ack led1 on");
+        assert_eq!(
+            CommandBuilder::new()
+                .state(LedState::ON)
+                .ack_requested(true)
+                .build(),
+            command
+        );
+        assert_eq!(parse(b"esp ack"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_led_token() {
+        const LED3: &[u8] = Led::Led3.token();
+        assert_eq!(LED3, b"led3");
+        for (led, token) in [
+            (Led::Led1, b"led1"),
+            (Led::Led2, b"led2"),
+            (Led::Led3, b"led3"),
+            (Led::Led4, b"led4"),
+        ] {
+            assert_eq!(led.token(), token);
+            assert_eq!(Led::from_slice(led.token()), Ok((&b""[..], led)));
+        }
+    }
+
+    #[test]
+    fn test_brightness_fraction() {
+        for (input, brightness) in [
+            ("esp led1 brightness 1/2", 127),
+            ("esp led1 brightness 3/4", 191),
+            ("esp led1 brightness 1/1", 255),
+            ("esp led1 brightness 0/9", 0),
+            ("esp led1 brightness 64", 64),
+            ("esp led1 on 1/2", 127),
+            ("esp led1 on 200", 200),
+        ] {
+            assert_eq!(
+                parse_str(input),
+                Ok(set_command(Led::Led1, LedState::On { brightness })),
+                "{input}"
+            );
+        }
+        for input in [
+            "esp led1 brightness 1/0",
+            "esp led1 brightness 3/2",
+            "esp led1 brightness 256",
+            "esp led1 brightness 1/",
+            "esp led1 brightness",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+        #[cfg(feature = "strict")]
+        assert_eq!(parse_str("esp led1 on 1/0"), Err(ParseError::UnknownState));
+        // An out of range value is a wrong state, not input after it.
+        #[cfg(feature = "strict")]
+        for input in ["esp led1 on 500", "esp led1 on 256 for 2s"] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+        assert_eq!(parse_str("esp led1 on x"), Err(ParseError::TrailingInput));
+        #[cfg(feature = "strict")]
+        assert_eq!(parse_str("esp led1 on 1 2"), Err(ParseError::UnknownState));
+    }
+
+    #[test]
+    fn test_command_stream_reset() {
+        let first = b"esp led1 on; esp led2 off";
+        let second = b"# comment; esp led1 on\nesp led3 on";
+        let mut stream = CommandStream::new(first);
+        assert_eq!(stream.next(), Some(set_command(Led::Led1, LedState::ON)));
+        // The first buffer is left after a ';', the comment still starts a line.
+        stream.reset(second);
+        assert_eq!(stream.next(), Some(set_command(Led::Led3, LedState::ON)));
+        assert_eq!(stream.next(), None);
+        stream.reset(first);
+        assert_eq!(stream.count(), 2);
+    }
+
+    #[test]
+    fn test_auto_off() {
+        let command = parse(b"esp led1 on for 500").unwrap();
+        assert_eq!(
+            command,
+            CommandBuilder::new()
+                .state(LedState::ON)
+                .auto_off_ms(500)
+                .build()
+        );
+        assert_eq!(parse(b"esp led1 on").unwrap().auto_off_ms, 0);
+        assert_eq!(
+            parse(b"esp led2 on 128 for 2s").map(|command| command.auto_off_ms),
+            Ok(2000)
+        );
+        let mut buf = [0u8; 24];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 on for 500");
+        for input in ["esp led1 on for", "esp led1 on for x", "esp led1 on for 0"] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidAutoOff), "{input}");
+        }
+        assert_eq!(
+            parse(b"esp led1 off for 500"),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(
+            parse(b"esp led1 on for 500 off"),
+            Err(ParseError::MultipleStates)
+        );
+    }
+
+    #[test]
+    fn test_split_token() {
+        for input in [
+            "esp le d1 on",
+            "esp l ed1 on",
+            "esp led1 o n",
+            "esp led2 of f",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::SplitToken), "{input}");
+        }
+        assert_eq!(parse_str("esp xyz on"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_str("esp lex d1 on"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_str("esp led1 o x"), Err(ParseError::UnknownState));
+        assert_eq!(
+            parse_positioned(b"esp led1 o n"),
+            Err(ParseErrorAt {
+                error: ParseError::SplitToken,
+                offset: 9
+            })
+        );
+    }
+
+    #[test]
+    fn test_command_batch() {
+        let input = b"esp led1 on; esp led9 on\n# comment\nesp led2 off";
+        let batch = CommandBatch::<4>::parse(input);
+        assert_eq!(
+            batch.as_slice(),
+            [
+                set_command(Led::Led1, LedState::ON),
+                Command::safe_default(),
+                set_command(Led::Led2, LedState::Off),
+            ]
+        );
+        assert!(!batch.is_truncated());
+        let batch = CommandBatch::<2>::parse(input);
+        assert_eq!(batch.as_slice().len(), 2);
+        assert!(batch.is_truncated());
+        assert!(!CommandBatch::<3>::parse(input).is_truncated());
+        assert!(CommandBatch::<0>::parse(b"").as_slice().is_empty());
+    }
+
+    #[test]
+    fn test_help() {
+        for input in ["esp", "ESP", "esp\r"] {
+            assert_eq!(
+                parse_str(input).map(|command| command.kind),
+                Ok(CommandKind::Help),
+                "{input}"
+            );
+            assert_eq!(Command::classify(input.as_bytes()), Some(CommandKind::Help));
+        }
+        let mut buf = [0u8; 8];
+        let length = parse(b"esp").unwrap().to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp");
+        assert_eq!(parse(b"esp xyz"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse(b"esp led1"), Err(ParseError::MissingState));
+        assert_eq!(parse(b"espx"), Err(ParseError::MissingPrefix));
+        assert_eq!(parse_no_prefix(b""), Command::safe_default());
+    }
+
+    #[test]
+    #[cfg(feature = "extended-states")]
+    fn test_target_mask() {
+        assert_eq!(parse(b"esp led1 on").unwrap().target_mask(), 0b0001);
+        assert_eq!(parse(b"esp led4 off").unwrap().target_mask(), 0b1000);
+        for input in ["esp clear", "esp mask 5", "esp invert"] {
+            assert_eq!(parse_str(input).unwrap().target_mask(), 0b1111, "{input}");
+        }
+        assert_eq!(parse(b"esp status").unwrap().target_mask(), 0);
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        let count = parse_list(b"esp led1,led3 on", &mut out).unwrap();
+        let mask = out[..count]
+            .iter()
+            .fold(0, |mask, command| mask | command.target_mask());
+        assert_eq!(mask, 0b0101);
+        // The mask covers exactly the leds `apply` changes.
+        let mut states = [LedState::Blink { period_ms: 1 }; MAX_LED as usize];
+        for command in &out[..count] {
+            command.apply(&mut states);
+        }
+        for (i, state) in states.iter().enumerate() {
+            assert_eq!(*state == LedState::ON, mask >> i & 1 == 1);
+        }
+    }
+
+    #[test]
+    fn test_parse_with_sep() {
+        assert_eq!(
+            parse_with_sep(b"esp:led1:on", b':'),
+            set_command(Led::Led1, LedState::ON)
+        );
+        #[cfg(feature = "extended-states")]
+        assert_eq!(
+            parse_with_sep(b"esp,led2,blink,500ms", b','),
+            set_command(Led::Led2, LedState::Blink { period_ms: 500 })
+        );
+        assert_eq!(
+            parse_with_sep(b"esp led3 off", b' '),
+            set_command(Led::Led3, LedState::Off)
+        );
+        for input in [
+            "esp:led1 on",
+            "esp led1 on",
+            "esp:led1:wfea",
+            "esp::led1:on",
+        ] {
+            assert_eq!(
+                parse_with_sep(input.as_bytes(), b':'),
+                Command::safe_default(),
+                "{input}"
+            );
+        }
+        let long = [b':'; MAX_FRAME_LEN + 1];
+        assert_eq!(parse_with_sep(&long, b':'), Command::safe_default());
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let empty = Token {
+            kind: TokenKind::Unknown,
+            text: b"",
+        };
+        let mut out = [empty; 8];
+        let token = |kind, text: &'static str| Token {
+            kind,
+            text: text.as_bytes(),
+        };
+        let count = Command::tokenize(b"esp led1 blink 500", &mut out);
+        assert_eq!(
+            out[..count],
+            [
+                token(TokenKind::Prefix, "esp"),
+                token(TokenKind::Led, "led1"),
+                token(TokenKind::State, "blink"),
+                token(TokenKind::Argument, "500"),
+            ]
+        );
+        let count = Command::tokenize(b"esp 3x !hi mask 5", &mut out);
+        assert_eq!(
+            out[..count],
+            [
+                token(TokenKind::Prefix, "esp"),
+                token(TokenKind::Modifier, "3x"),
+                token(TokenKind::Modifier, "!hi"),
+                token(TokenKind::Keyword, "mask"),
+                token(TokenKind::Argument, "5"),
+            ]
+        );
+        let count = Command::tokenize(b"esp led2 hold wfea", &mut out);
+        assert_eq!(out[2].kind, TokenKind::Modifier);
+        assert_eq!(out[count - 1], token(TokenKind::Unknown, "wfea"));
+        assert_eq!(Command::tokenize(b"esp led1 on", &mut out[..2]), 2);
+        assert_eq!(Command::tokenize(b"  ", &mut out), 0);
+    }
+
+    #[test]
+    fn test_empty_command() {
+        for input in ["esp ", "esp   ", "esp \t\r", "esp@2 "] {
+            assert_eq!(parse_str(input), Err(ParseError::EmptyCommand), "{input}");
+            assert_eq!(Command::classify(input.as_bytes()), None, "{input}");
+        }
+        assert_eq!(
+            parse_positioned(b"esp   "),
+            Err(ParseErrorAt {
+                error: ParseError::EmptyCommand,
+                offset: 4
+            })
+        );
+        assert_eq!(parse(b"esp xyz"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_all_except() {
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp all~led2 on", &mut out), Ok(3));
+        assert_eq!(
+            out[..3],
+            [
+                set_command(Led::Led1, LedState::ON),
+                set_command(Led::Led3, LedState::ON),
+                set_command(Led::Led4, LedState::ON),
+            ]
+        );
+        assert_eq!(parse_list(b"esp !hi all~l4 off", &mut out), Ok(3));
+        assert_eq!(out[2].led, Led::Led3);
+        let before = out;
+        for input in [
+            "esp all~led9 on",
+            "esp all~ on",
+            "esp all~xyz on",
+            "esp all~led1,led2 on",
+        ] {
+            assert_eq!(
+                parse_list(input.as_bytes(), &mut out),
+                Err(ParseError::UnknownLed),
+                "{input}"
+            );
+        }
+        assert_eq!(out, before);
+    }
+
+    #[test]
+    fn test_toggle() {
+        let command = parse(b"esp led2 toggle").unwrap();
+        assert_eq!(command, set_command(Led::Led2, LedState::Toggle));
+        let mut states = [LedState::Off; MAX_LED as usize];
+        command.apply(&mut states);
+        assert_eq!(states[1], LedState::ON);
+        command.apply(&mut states);
+        assert_eq!(states, [LedState::Off; MAX_LED as usize]);
+        let mut buf = [0u8; 24];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led2 toggle");
+        assert_eq!(command.opcode(), 0x0102);
+        assert_eq!(command.to_basic(), None);
+        assert_eq!(parse(b"esp led2 toggled"), Err(ParseError::UnknownState));
+    }
+
+    #[test]
+    #[cfg(not(feature = "extended-states"))]
+    fn test_command_layout_small() {
+        use core::mem::{offset_of, size_of};
+
+        // Without the states carrying values a LedState is 8 bytes, the fields after it move
+        // up by 4. See `test_command_layout`.
+        const _: () = assert!(size_of::<LedState>() == 8);
+        const _: () = assert!(size_of::<Command>() == 64);
+        const _: () = assert!(offset_of!(Command, state) == 8);
+        const _: () = assert!(offset_of!(Command, kind) == 16);
+        const _: () = assert!(offset_of!(Command, repeat) == 48);
+        const _: () = assert!(offset_of!(Command, priority) == 49);
+        const _: () = assert!(offset_of!(Command, seq) == 50);
+        const _: () = assert!(offset_of!(Command, at_tick) == 52);
+        const _: () = assert!(offset_of!(Command, latched) == 56);
+        const _: () = assert!(offset_of!(Command, addr) == 57);
+        const _: () = assert!(offset_of!(Command, ack_requested) == 58);
+        const _: () = assert!(offset_of!(Command, auto_off_ms) == 60);
+        const _: () = assert!(offset_of!(Command, fade_in_ms) == 62);
+        for input in [
+            "esp led1 blink 500ms",
+            "esp led1 rgb 1 2 3",
+            "esp led1 color red",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_at() {
+        let input = b"esp led1 on; esp led2 off\n# lights\n\nesp led9 on;; esp status\n";
+        let mut cursor = 0;
+        assert_eq!(
+            Command::parse_at(input, &mut cursor),
+            Some(set_command(Led::Led1, LedState::ON))
+        );
+        assert_eq!(cursor, 12);
+        let mut commands = [Command::safe_default(); 4];
+        let mut count = 0;
+        while let Some(command) = Command::parse_at(input, &mut cursor) {
+            commands[count] = command;
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert_eq!(commands[0], set_command(Led::Led2, LedState::Off));
+        assert!(!commands[1].success);
+        assert_eq!(commands[2].kind, CommandKind::Status);
+        assert_eq!(cursor, input.len());
+        assert_eq!(Command::parse_at(input, &mut cursor), None);
+        // After a ';' the line goes on, a '#' is no comment.
+        let mut cursor = 12;
+        let command = Command::parse_at(b"esp led1 on;# x\nesp led2 on", &mut cursor);
+        assert_eq!(command.map(|command| command.success), Some(false));
+        assert_eq!(cursor, 16);
+        let mut cursor = 99;
+        assert_eq!(Command::parse_at(input, &mut cursor), None);
+        assert_eq!(cursor, 99);
+    }
+
+    #[test]
+    fn test_all_brightness() {
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp all brightness 64", &mut out), Ok(4));
+        let dimmed = LedState::On { brightness: 64 };
+        assert_eq!(
+            out,
+            [
+                set_command(Led::Led1, dimmed),
+                set_command(Led::Led2, dimmed),
+                set_command(Led::Led3, dimmed),
+                set_command(Led::Led4, dimmed),
+            ]
+        );
+        let mask = out
+            .iter()
+            .fold(0, |mask, command| mask | command.target_mask());
+        assert_eq!(mask, 0b1111);
+        let mut states = [LedState::Off; MAX_LED as usize];
+        for command in &out {
+            command.apply(&mut states);
+        }
+        assert_eq!(states, [dimmed; MAX_LED as usize]);
+        assert_eq!(parse_list(b"esp 2x all off", &mut out), Ok(4));
+        assert_eq!(out[3].repeat, 2);
+        let before = out;
+        assert_eq!(
+            parse_list(b"esp all brightness 256", &mut out),
+            Err(ParseError::UnknownState)
+        );
+        assert_eq!(
+            parse_list(b"esp all,led1 on", &mut out),
+            Err(ParseError::UnknownLed)
+        );
+        assert_eq!(out, before);
+    }
+
+    #[test]
+    fn test_control_char() {
+        for input in [
+            &b"esp led1 \x1b[0mon"[..],
+            b"esp led1 o\x08on",
+            b"esp\x7f led1 on",
+            b"esp led1 on\x00",
+        ] {
+            assert_eq!(parse(input), Err(ParseError::ControlChar), "{input:?}");
+        }
+        assert_eq!(
+            parse_positioned(b"esp led1 \x1b[0mon"),
+            Err(ParseErrorAt {
+                error: ParseError::ControlChar,
+                offset: 9
+            })
+        );
+        assert_eq!(
+            parse(b"esp led1 on \t\r"),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+        // A binary trailer is not part of the command.
+        let led1_on = set_command(Led::Led1, LedState::ON);
+        for input in [&b"esp led1 on \x03"[..], b"esp led1 on \x80"] {
+            assert_eq!(parse_lenient(input), Ok((led1_on, true)), "{input:?}");
+        }
+        let parsed = ParsedCommand::Set {
+            led: Led::Led1,
+            state: LedState::ON,
+        };
+        assert_eq!(
+            command_parser(b"esp led1 on\x03rest"),
+            Ok((&b"\x03rest"[..], parsed))
+        );
+        assert_eq!(
+            command_parser(b"esp led1 on\x01"),
+            Ok((&b"\x01"[..], parsed))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "heapless")]
+    fn test_parse_vec() {
+        let mut buffer = heapless::Vec::<u8, MAX_FRAME_LEN>::new();
+        buffer.extend_from_slice(b"esp led3 off").unwrap();
+        assert_eq!(parse_vec(&buffer), set_command(Led::Led3, LedState::Off));
+        buffer.clear();
+        buffer.extend_from_slice(b"esp led3 wfea").unwrap();
+        assert_eq!(parse_vec(&buffer), Command::safe_default());
+    }
+
+    #[test]
+    fn test_cycle() {
+        let command = parse(b"esp cycle 200").unwrap();
+        assert_eq!(command.kind, CommandKind::Cycle { step_ms: 200 });
+        assert_eq!(
+            parse(b"esp 2x cycle 1s").map(|command| (command.kind, command.repeat)),
+            Ok((CommandKind::Cycle { step_ms: 1000 }, 2))
+        );
+        let mut buf = [0u8; 16];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp cycle 200");
+        assert_eq!(
+            Command::classify(b"esp cycle 200"),
+            Some(CommandKind::Cycle { step_ms: 0 })
+        );
+        let mut states = [LedState::ON; MAX_LED as usize];
+        command.apply(&mut states);
+        assert_eq!(states, [LedState::Off; MAX_LED as usize]);
+        for input in [
+            "esp cycle",
+            "esp cycle ",
+            "esp cycle 0",
+            "esp cycle x",
+            "esp cycle 200x",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidCycle), "{input}");
+        }
+        assert_eq!(parse(b"esp cycles 200"), Err(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn test_strict_matching() {
+        #[cfg(feature = "strict")]
+        for input in ["esp led11 on", "esp led2 oon", "esp xled3 offasdf"] {
+            assert!(parse_str(input).is_err(), "{input}");
+        }
+        #[cfg(not(feature = "strict"))]
+        {
+            assert_eq!(
+                parse(b"esp led11 on"),
+                Ok(set_command(Led::Led1, LedState::ON))
+            );
+            assert_eq!(
+                parse(b"esp 2x led2 oon"),
+                Ok(Command {
+                    repeat: 2,
+                    ..set_command(Led::Led2, LedState::ON)
+                })
+            );
+            assert_eq!(
+                parse(b"esp xled3 offasdf"),
+                Ok(set_command(Led::Led3, LedState::Off))
+            );
+            assert_eq!(parse(b"esp led9 on"), Err(ParseError::UnknownLed));
+            assert_eq!(parse(b"esp led1 wfea"), Err(ParseError::UnknownState));
+        }
+        assert_eq!(
+            parse(b"esp led1 on 128").unwrap().state,
+            LedState::On { brightness: 128 }
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let on = set_command(Led::Led1, LedState::ON);
+        assert_eq!(on.diff(&on), 0);
+        assert_eq!(on.diff(&Command { repeat: 3, ..on }), 0);
+        assert_eq!(on.diff(&set_command(Led::Led2, LedState::ON)), DIFF_LED);
+        assert_eq!(on.diff(&set_command(Led::Led1, LedState::Off)), DIFF_STATE);
+        assert_eq!(
+            on.diff(&set_command(Led::Led1, LedState::On { brightness: 128 })),
+            DIFF_PAYLOAD
+        );
+        assert_eq!(
+            on.diff(&set_command(Led::Led4, LedState::Toggle)),
+            DIFF_LED | DIFF_STATE
+        );
+    }
+
+    #[test]
+    fn test_fade_in() {
+        let command = parse(b"esp led1 on fadein 300").unwrap();
+        assert_eq!(
+            command,
+            CommandBuilder::new()
+                .state(LedState::ON)
+                .fade_in_ms(300)
+                .build()
+        );
+        assert_eq!(parse(b"esp led1 on").unwrap().fade_in_ms, 0);
+        let command = parse(b"esp led2 on 128 fadein 1s for 5s").unwrap();
+        assert_eq!((command.fade_in_ms, command.auto_off_ms), (1000, 5000));
+        let mut buf = [0u8; 40];
+        let length = command.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led2 on 128 fadein 1000 for 5000");
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp led1,led3 on fadein 300", &mut out), Ok(2));
+        assert_eq!(out[1].fade_in_ms, 300);
+        for input in [
+            "esp led1 on fadein",
+            "esp led1 on fadein x",
+            "esp led1 on fadein 0",
+        ] {
+            assert_eq!(parse_str(input), Err(ParseError::InvalidFadeIn), "{input}");
+        }
+        // An off led has nothing to fade in.
+        assert_eq!(
+            parse(b"esp led1 off fadein 300"),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(
+            parse(b"esp led1 on for 5s fadein 300"),
+            Err(ParseError::TrailingInput)
+        );
+    }
+
+    #[test]
+    fn test_apply_line_edits() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        for (input, edited) in [
+            (&b"esp led2\x7f1 on"[..], &b"esp led1 on"[..]),
+            (b"esp led1 of\x08\x08on", b"esp led1 on"),
+            (b"\x7f\x7fesp led1 on", b"esp led1 on"),
+            (b"esp led1 on\x08\x08off", b"esp led1 off"),
+            (b"ab\x7f\x7f\x7f", b""),
+        ] {
+            let length = apply_line_edits(input, &mut buf).unwrap();
+            assert_eq!(&buf[..length], edited, "{input:?}");
+        }
+        assert_eq!(parse(b"esp led2\x7f1 on"), Err(ParseError::ControlChar));
+        let length = apply_line_edits(b"esp led2\x7f1 on", &mut buf).unwrap();
+        assert_eq!(
+            parse(&buf[..length]),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+        let mut small = [0u8; 3];
+        assert_eq!(apply_line_edits(b"esp led1 on", &mut small), None);
+        assert_eq!(apply_line_edits(b"abcd\x7f", &mut small), Some(3));
+        assert_eq!(&small, b"abc");
+        assert_eq!(apply_line_edits(b"abcd\x7f\x7fz", &mut small), Some(3));
+        assert_eq!(&small, b"abz");
+        assert_eq!(apply_line_edits(b"abcde\x7f", &mut small), None);
+    }
+
+    #[test]
+    fn test_short_states() {
+        assert_eq!(
+            parse(b"esp led1 n"),
+            Ok(set_command(Led::Led1, LedState::ON))
+        );
+        assert_eq!(
+            parse(b"esp led4 f"),
+            Ok(set_command(Led::Led4, LedState::Off))
+        );
+        assert_eq!(
+            parse(b"esp led2 n 64"),
+            Ok(set_command(Led::Led2, LedState::On { brightness: 64 }))
+        );
+        let mut buf = [0u8; 16];
+        let length = parse(b"esp led1 f").unwrap().to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"esp led1 off");
+        for input in ["esp led1 x", "esp led1 nf", "esp led1 fo", "esp led1 N"] {
+            assert_eq!(parse_str(input), Err(ParseError::UnknownState), "{input}");
+        }
+        assert_eq!(parse(b"esp led1 n f"), Err(ParseError::MultipleStates));
+    }
+
+    #[test]
+    fn test_validate_all() {
+        assert_eq!(
+            validate_all(b"# batch\nesp led1 on; esp led2 off\nesp status\n"),
+            Ok(3)
+        );
+        assert_eq!(validate_all(b""), Ok(0));
+        assert_eq!(
+            validate_all(b"esp led1 on;; esp led9 on\nesp led1 wfea"),
+            Err((1, ParseError::UnknownLed))
+        );
+        assert_eq!(
+            validate_all(b"esp x; esp led1 on"),
+            Err((0, ParseError::UnknownCommand))
+        );
+    }
+
+    #[test]
+    fn test_addressed_entry_points() {
+        assert_eq!(Command::classify(b"esp@2 led1 on"), Some(CommandKind::Set));
+        assert_eq!(
+            Command::classify(b"esp@2 3x status"),
+            Some(CommandKind::Status)
+        );
+        assert_eq!(Command::classify(b"esp@x led1 on"), None);
+        let addressed = |led| Command {
+            addr: 2,
+            ..set_command(led, LedState::ON)
+        };
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(parse_list(b"esp@2 led1,led2 on", &mut out), Ok(2));
+        assert_eq!(out[..2], [addressed(Led::Led1), addressed(Led::Led2)]);
+        assert_eq!(
+            parse_list(b"esp@x led1,led2 on", &mut out),
+            Err(ParseError::InvalidAddress)
+        );
+        const GROUPS: &[(&[u8], &[Led])] = &[(b"front", &[Led::Led1, Led::Led2])];
+        let mut commands = [Command::safe_default(); 2];
+        assert_eq!(parse_group(b"esp@2 front on", GROUPS, &mut commands), 2);
+        assert_eq!(commands, [addressed(Led::Led1), addressed(Led::Led2)]);
+        assert_eq!(parse_group(b"esp@x front on", GROUPS, &mut commands), 0);
+    }
+
+    #[test]
+    fn test_group_trailing_input() {
+        const GROUPS: &[(&[u8], &[Led])] = &[(b"front", &[Led::Led1, Led::Led2])];
+        let mut commands = [Command::safe_default(); 2];
+        assert_eq!(parse_group(b"esp front on \r", GROUPS, &mut commands), 2);
+        for input in [
+            &b"esp front on junk junk"[..],
+            b"esp front on \xc3",
+            b"esp front o\x08on",
+            &[b'x'; MAX_FRAME_LEN + 1],
+        ] {
+            assert_eq!(parse_group(input, GROUPS, &mut commands), 0, "{input:?}");
+        }
+        let mut out = [Command::safe_default(); MAX_LED as usize];
+        assert_eq!(
+            parse_list(b"esp led1,led2 on junk", &mut out),
+            Err(ParseError::TrailingInput)
+        );
+        assert_eq!(
+            parse_list(b"esp led1,led2 on\x1b", &mut out),
+            Err(ParseError::ControlChar)
         );
     }
 }